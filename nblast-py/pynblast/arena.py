@@ -69,6 +69,46 @@ class NblastArena:
 
         return self._impl.add_points_tangents(points.tolist(), tangents.tolist())
 
+    def add_points_parents(
+        self, points: np.ndarray, parents: List[Optional[int]]
+    ) -> Idx:
+        """Add an Nx3 point cloud representing a neuron's skeleton,
+        where ``parents`` gives the index of each point's parent, or ``None`` for a root.
+        Tangents are calculated from the skeleton's edges rather than nearby points.
+
+        Returns an integer index which is used to refer to that neuron later.
+        """
+        if points.ndim != 2 or points.shape[1] != 3:
+            raise ValueError("Points must have shape Nx3")
+        if len(parents) != len(points):
+            raise ValueError("There must be one parent per point")
+        parents = [None if p is None else int(p) for p in parents]
+        if any(p is not None and not 0 <= p < len(points) for p in parents):
+            raise ValueError("Parents must be None or the index of another point")
+
+        return self._impl.add_points_parents(points.tolist(), parents)
+
+    def add_named_points(self, name: str, points: np.ndarray) -> Idx:
+        """Add an Nx3 point cloud representing a neuron, as ``add_points``,
+        with a unique name which can be used to look up its index.
+        """
+        if points.ndim != 2 or points.shape[1] != 3:
+            raise ValueError("Points must have shape Nx3")
+        return self._impl.add_named_points(name, points.tolist())
+
+    def idx_by_name(self, name: str) -> Idx:
+        """Return the index of the neuron with the given name."""
+        out = self._impl.idx_by_name(name)
+        if out is None:
+            raise KeyError(f"Name not in arena: {name}")
+        return Idx(out)
+
+    def set_metadata(self, idx: Idx, key: str, value: str) -> Optional[str]:
+        """Set a metadata value of the indexed neuron, e.g. ``("cell_type", "KC")``,
+        returning the previous value for that key.
+        """
+        return self._impl.set_metadata(idx, key, value)
+
     def query_target(
         self,
         query_idx: Idx,
@@ -105,6 +145,22 @@ class NblastArena:
             query_idxs, target_idxs, bool(normalize), symmetry
         )
 
+    def queries_targets_where(
+        self,
+        query_idxs: List[Idx],
+        target_filter: Dict[str, str],
+        normalize: bool = False,
+        symmetry: Optional[Symmetry] = None,
+    ) -> Dict[Tuple[Idx, Idx], float]:
+        """Query some query neurons against every target neuron whose metadata
+        has all of the given key/value pairs.
+
+        See the ``query_target`` method for more details.
+        """
+        return self._impl.queries_targets_where(
+            query_idxs, list(target_filter.items()), bool(normalize), symmetry
+        )
+
     def all_v_all(self, normalize=False, symmetry=None) -> Dict[Tuple[Idx, Idx], float]:
         """Query all loaded neurons against each other.
 
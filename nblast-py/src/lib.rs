@@ -45,16 +45,25 @@ impl ArenaWrapper {
         cells: Vec<f64>,
         k: usize,
     ) -> PyResult<()> {
-        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells);
+        let score_fn = table_to_fn::<Precision>(dist_thresholds, dot_thresholds, cells)
+            .map_err(|e| PyErr::new::<exceptions::ValueError, _>(e.to_string()))?;
         Ok(obj.init(Self {
-            arena: NblastArena::new(Box::new(score_fn)), k,
+            arena: NblastArena::new(Box::new(score_fn), false), k,
         }))
     }
 
     fn add_points(&mut self, _py: Python, points: Vec<Vec<f64>>) -> PyResult<usize> {
         // TODO: avoid this copy?
         let neuron = RStarPointTangents::new(points.iter().map(vec_to_array3).collect(), self.k)
-            .map_err(|s| PyErr::new::<exceptions::RuntimeError, _>(s))?;
+            .map_err(|e| PyErr::new::<exceptions::RuntimeError, _>(e.to_string()))?;
+        Ok(self.arena.add_neuron(neuron))
+    }
+
+    fn add_points_parents(&mut self, _py: Python, points: Vec<Vec<f64>>, parents: Vec<Option<usize>>) -> PyResult<usize> {
+        let neuron = RStarPointTangents::new_with_parents(
+            points.iter().map(vec_to_array3).collect(),
+            &parents,
+        ).map_err(|e| PyErr::new::<exceptions::RuntimeError, _>(e.to_string()))?;
         Ok(self.arena.add_neuron(neuron))
     }
 
@@ -62,10 +71,28 @@ impl ArenaWrapper {
         let neuron = RStarPointTangents::new_with_tangents(
             points.iter().map(vec_to_array3).collect(),
             tangents.iter().map(vec_to_unitvector3).collect(),
-        ).map_err(|s| PyErr::new::<exceptions::RuntimeError, _>(s))?;
+        ).map_err(|e| PyErr::new::<exceptions::RuntimeError, _>(e.to_string()))?;
         Ok(self.arena.add_neuron(neuron))
     }
 
+    fn add_named_points(&mut self, _py: Python, name: &str, points: Vec<Vec<f64>>) -> PyResult<usize> {
+        let neuron = RStarPointTangents::new(points.iter().map(vec_to_array3).collect(), self.k)
+            .map_err(|e| PyErr::new::<exceptions::RuntimeError, _>(e.to_string()))?;
+        self.arena
+            .add_named_neuron(name, neuron)
+            .map_err(|e| PyErr::new::<exceptions::ValueError, _>(e.to_string()))
+    }
+
+    pub fn idx_by_name(&self, _py: Python, name: &str) -> Option<NeuronIdx> {
+        self.arena.idx_by_name(name)
+    }
+
+    pub fn set_metadata(&mut self, _py: Python, idx: NeuronIdx, key: &str, value: &str) -> PyResult<Option<String>> {
+        self.arena
+            .set_metadata(idx, key, value)
+            .map_err(|e| PyErr::new::<exceptions::IndexError, _>(e.to_string()))
+    }
+
     pub fn query_target(
         &self,
         _py: Python,
@@ -99,6 +126,23 @@ impl ArenaWrapper {
             .queries_targets(&query_idxs, &target_idxs, normalize, &sym))
     }
 
+    pub fn queries_targets_where(
+        &self,
+        _py: Python,
+        query_idxs: Vec<NeuronIdx>,
+        target_filter: Vec<(String, String)>,
+        normalize: bool,
+        symmetry: Option<&str>,
+    ) -> PyResult<HashMap<(NeuronIdx, NeuronIdx), f64>> {
+        let sym = match symmetry {
+            Some(s) => Some(str_to_sym(s).map_err(|_| PyErr::new::<exceptions::ValueError, _>("Symmetry type not recognised"))?),
+            _ => None,
+        };
+        let filter: Vec<_> = target_filter.iter().map(|(k, v)| (k.as_str(), v.as_str())).collect();
+        Ok(self.arena
+            .queries_targets_where(&query_idxs, &filter, normalize, &sym))
+    }
+
     pub fn all_v_all(
         &self,
         _py: Python,
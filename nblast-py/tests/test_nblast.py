@@ -146,3 +146,32 @@ def test_prepop_tangents(points, arena):
     assert arena.query_target(idx0, idx1, normalize=True) == pytest.approx(
         1.0, abs=EPSILON
     )
+
+
+def test_named_metadata(points, arena):
+    idxs = {}
+    for name, df in points[:3]:
+        idxs[name] = arena.add_named_points(name, df.to_numpy())
+    name0, name1, name2 = list(idxs)
+    assert arena.idx_by_name(name1) == idxs[name1]
+    with pytest.raises(KeyError):
+        arena.idx_by_name("not a neuron")
+    with pytest.raises(ValueError):
+        arena.add_named_points(name0, points[0][1].to_numpy())
+
+    assert arena.set_metadata(idxs[name1], "cell_type", "KC") is None
+    assert arena.set_metadata(idxs[name2], "cell_type", "KC") is None
+    out = arena.queries_targets_where([idxs[name0]], {"cell_type": "KC"})
+    assert out == arena.queries_targets([idxs[name0]], [idxs[name1], idxs[name2]])
+
+
+def test_points_parents(arena):
+    points = np.array([[float(i), 0.0, 0.0] for i in range(10)])
+    parents = [None] + list(range(9))
+    idx = arena.add_points_parents(points, parents)
+    assert np.allclose(arena.tangents(idx, True), [[1.0, 0.0, 0.0]] * 10)
+
+    with pytest.raises(ValueError):
+        arena.add_points_parents(points, parents[:-1])
+    with pytest.raises(ValueError):
+        arena.add_points_parents(points, [None] + [10] * 9)
@@ -6,7 +6,10 @@ use std::path::PathBuf;
 use bencher::{benchmark_group, benchmark_main, Bencher};
 use csv::ReaderBuilder;
 
-use nblast::{table_to_fn, DistDot, NblastArena, QueryNeuron, RStarPointTangents};
+use nblast::spatial::{BruteForce, DistanceField, GridHash, KdTree, RStarIndex, SpatialIndex};
+use nblast::{
+    DistDot, IndexedPointTangents, NblastArena, QueryNeuron, RStarPointTangents, ScoreMatrix,
+};
 
 const NAMES: [&str; 20] = [
     "ChaMARCM-F000586_seg002",
@@ -75,63 +78,14 @@ fn read_points(name: &str) -> Vec<[Precision; 3]> {
     out
 }
 
-fn parse_interval(s: &str) -> (f64, f64) {
-    let substr = &s[1..s.len() - 1];
-    let split_idx = substr.find(',').expect("no comma");
-    let l_r = substr.split_at(split_idx);
-    let r = l_r.1.split_at(1);
-    (
-        l_r.0
-            .parse::<f64>()
-            .unwrap_or_else(|_| panic!(format!("lower bound not float: '{}'", l_r.0))),
-        r.1.parse::<f64>()
-            .unwrap_or_else(|_| panic!(format!("upper bound not float: '{}'", r.1))),
-    )
-}
-
-/// dist thresholds, dot thresholds, cells
-fn read_smat() -> (Vec<Precision>, Vec<Precision>, Vec<Precision>) {
+fn get_score_fn() -> impl Fn(&DistDot) -> Precision {
     let mut d = data_dir();
     d.push("smat_fcwb.csv");
 
     let f = File::open(d).expect("file not found");
-    let mut reader = ReaderBuilder::new()
-        .has_headers(false)
-        .flexible(true)
-        .from_reader(f);
-
-    let mut dot_tresholds = Vec::new();
-
-    let mut results = reader.records();
-    let first_row = results.next().expect("no first row").expect("bad parse");
-
-    // drop first (empty) column
-    let mut first_row_iter = first_row.iter();
-    first_row_iter.next();
-    for dot_interval_str in first_row_iter {
-        let dot_interval = parse_interval(dot_interval_str);
-        dot_tresholds.push(dot_interval.1);
-    }
-
-    let mut dist_thresholds = Vec::new();
-    let mut cells = Vec::new();
-    for result in reader.records() {
-        let record = result.expect("failed record");
-        let mut record_iter = record.iter();
-        let dist_interval_str = record_iter.next().expect("No first item");
-        let dist_interval = parse_interval(dist_interval_str);
-        dist_thresholds.push(dist_interval.1);
-
-        for cell in record_iter {
-            cells.push(cell.parse::<f64>().expect("cell not float"));
-        }
-    }
-    (dist_thresholds, dot_tresholds, cells)
-}
-
-fn get_score_fn() -> impl Fn(&DistDot) -> Precision {
-    let args = read_smat();
-    table_to_fn(args.0, args.1, args.2)
+    ScoreMatrix::from_csv(f)
+        .expect("couldn't parse score matrix")
+        .to_fn()
 }
 
 fn bench_query(b: &mut Bencher) {
@@ -139,7 +93,7 @@ fn bench_query(b: &mut Bencher) {
     let query = RStarPointTangents::new(read_points(NAMES[0]), N_NEIGHBORS).expect("couldn't parse");
     let target = RStarPointTangents::new(read_points(NAMES[1]), N_NEIGHBORS).expect("couldn't parse");
 
-    b.iter(|| query.query(&target, &score_fn))
+    b.iter(|| query.query(&target, false, &score_fn))
 }
 
 fn bench_rstarpt_construction(b: &mut Bencher) {
@@ -155,6 +109,89 @@ fn bench_rstarpt_construction_with_tangents(b: &mut Bencher) {
     b.iter(|| RStarPointTangents::new_with_tangents(points.clone(), tangents.clone()));
 }
 
+fn bench_index_construction<I: SpatialIndex<Precision>>(b: &mut Bencher) {
+    let neuron = RStarPointTangents::new(read_points(NAMES[0]), N_NEIGHBORS).expect("couldn't parse");
+    let points = neuron.points();
+    let tangents = neuron.tangents();
+    b.iter(|| {
+        IndexedPointTangents::<Precision, I>::new_with_tangents(points.clone(), tangents.clone())
+    });
+}
+
+fn bench_index_query<I: SpatialIndex<Precision>>(b: &mut Bencher) {
+    let score_fn = get_score_fn();
+    let query = RStarPointTangents::new(read_points(NAMES[0]), N_NEIGHBORS).expect("couldn't parse");
+    let target = IndexedPointTangents::<Precision, I>::new(read_points(NAMES[1]), N_NEIGHBORS)
+        .expect("couldn't parse");
+
+    b.iter(|| query.query(&target, false, &score_fn))
+}
+
+fn bench_index_all_to_all<I: SpatialIndex<Precision>>(b: &mut Bencher) {
+    let mut arena = NblastArena::new(get_score_fn(), false);
+    let mut idxs = Vec::new();
+    for name in NAMES.iter() {
+        let points = read_points(name);
+        idxs.push(arena.add_neuron(
+            IndexedPointTangents::<Precision, I>::new(points, N_NEIGHBORS).expect("couldn't parse"),
+        ));
+    }
+
+    b.iter(|| arena.queries_targets(&idxs, &idxs, false, &None));
+}
+
+fn bench_rstar_construction(b: &mut Bencher) {
+    bench_index_construction::<RStarIndex<Precision>>(b)
+}
+
+fn bench_kdtree_construction(b: &mut Bencher) {
+    bench_index_construction::<KdTree<Precision>>(b)
+}
+
+fn bench_grid_construction(b: &mut Bencher) {
+    bench_index_construction::<GridHash<Precision>>(b)
+}
+
+fn bench_field_construction(b: &mut Bencher) {
+    bench_index_construction::<DistanceField<Precision>>(b)
+}
+
+fn bench_rstar_query(b: &mut Bencher) {
+    bench_index_query::<RStarIndex<Precision>>(b)
+}
+
+fn bench_kdtree_query(b: &mut Bencher) {
+    bench_index_query::<KdTree<Precision>>(b)
+}
+
+fn bench_grid_query(b: &mut Bencher) {
+    bench_index_query::<GridHash<Precision>>(b)
+}
+
+fn bench_bruteforce_query(b: &mut Bencher) {
+    bench_index_query::<BruteForce<Precision>>(b)
+}
+
+fn bench_field_query(b: &mut Bencher) {
+    bench_index_query::<DistanceField<Precision>>(b)
+}
+
+fn bench_rstar_all_to_all(b: &mut Bencher) {
+    bench_index_all_to_all::<RStarIndex<Precision>>(b)
+}
+
+fn bench_kdtree_all_to_all(b: &mut Bencher) {
+    bench_index_all_to_all::<KdTree<Precision>>(b)
+}
+
+fn bench_grid_all_to_all(b: &mut Bencher) {
+    bench_index_all_to_all::<GridHash<Precision>>(b)
+}
+
+fn bench_field_all_to_all(b: &mut Bencher) {
+    bench_index_all_to_all::<DistanceField<Precision>>(b)
+}
+
 fn bench_arena_construction(b: &mut Bencher) {
     let score_fn = get_score_fn();
     let pointtangents: Vec<_> = NAMES
@@ -162,7 +199,7 @@ fn bench_arena_construction(b: &mut Bencher) {
         .map(|n| RStarPointTangents::new(read_points(n), N_NEIGHBORS).expect("couldn't parse"))
         .collect();
     b.iter(|| {
-        let mut arena = NblastArena::new(&score_fn);
+        let mut arena = NblastArena::new(&score_fn, false);
         for dp in pointtangents.iter().cloned() {
             arena.add_neuron(dp);
         }
@@ -170,7 +207,7 @@ fn bench_arena_construction(b: &mut Bencher) {
 }
 
 fn bench_arena_query(b: &mut Bencher) {
-    let mut arena = NblastArena::new(get_score_fn());
+    let mut arena = NblastArena::new(get_score_fn(), false);
     let p0 = read_points(NAMES[0]);
     let idx0 = arena.add_neuron(RStarPointTangents::new(p0, N_NEIGHBORS).expect("couldn't parse"));
     let p1 = read_points(NAMES[1]);
@@ -180,7 +217,7 @@ fn bench_arena_query(b: &mut Bencher) {
 }
 
 fn bench_arena_query_norm(b: &mut Bencher) {
-    let mut arena = NblastArena::new(get_score_fn());
+    let mut arena = NblastArena::new(get_score_fn(), false);
     let p0 = read_points(NAMES[0]);
     let idx0 = arena.add_neuron(RStarPointTangents::new(p0, N_NEIGHBORS).expect("couldn't parse"));
     let p1 = read_points(NAMES[1]);
@@ -190,7 +227,7 @@ fn bench_arena_query_norm(b: &mut Bencher) {
 }
 
 fn bench_arena_query_geom(b: &mut Bencher) {
-    let mut arena = NblastArena::new(get_score_fn());
+    let mut arena = NblastArena::new(get_score_fn(), false);
     let p0 = read_points(NAMES[0]);
     let idx0 = arena.add_neuron(RStarPointTangents::new(p0, N_NEIGHBORS).expect("couldn't parse"));
     let p1 = read_points(NAMES[1]);
@@ -200,7 +237,7 @@ fn bench_arena_query_geom(b: &mut Bencher) {
 }
 
 fn bench_arena_query_norm_geom(b: &mut Bencher) {
-    let mut arena = NblastArena::new(get_score_fn());
+    let mut arena = NblastArena::new(get_score_fn(), false);
     let p0 = read_points(NAMES[0]);
     let idx0 = arena.add_neuron(RStarPointTangents::new(p0, N_NEIGHBORS).expect("couldn't parse"));
     let p1 = read_points(NAMES[1]);
@@ -210,7 +247,7 @@ fn bench_arena_query_norm_geom(b: &mut Bencher) {
 }
 
 fn bench_all_to_all(b: &mut Bencher) {
-    let mut arena = NblastArena::new(get_score_fn());
+    let mut arena = NblastArena::new(get_score_fn(), false);
     let mut idxs = Vec::new();
     for name in NAMES.iter() {
         let points = read_points(name);
@@ -220,6 +257,35 @@ fn bench_all_to_all(b: &mut Bencher) {
     b.iter(|| arena.queries_targets(&idxs, &idxs, false, &None));
 }
 
+fn bench_all_to_all_max_dist(b: &mut Bencher) {
+    let mut d = data_dir();
+    d.push("smat_fcwb.csv");
+    let smat = ScoreMatrix::from_csv(File::open(d).expect("file not found"))
+        .expect("couldn't parse score matrix");
+    // this table's outermost row depends on the dot product, so opt in to the cutoff:
+    // this changes the scores of distant point matches, but not the time saved
+    let mut arena = NblastArena::from_score_matrix_with_cutoff(smat, false);
+    let mut idxs = Vec::new();
+    for name in NAMES.iter() {
+        let points = read_points(name);
+        idxs.push(arena.add_neuron(RStarPointTangents::new(points, N_NEIGHBORS).expect("couldn't parse")));
+    }
+
+    b.iter(|| arena.queries_targets(&idxs, &idxs, false, &None));
+}
+
+#[cfg(feature = "parallel")]
+fn bench_all_to_all_par(b: &mut Bencher) {
+    let mut arena = NblastArena::new(get_score_fn(), false);
+    let mut idxs = Vec::new();
+    for name in NAMES.iter() {
+        let points = read_points(name);
+        idxs.push(arena.add_neuron(RStarPointTangents::new(points, N_NEIGHBORS).expect("couldn't parse")));
+    }
+
+    b.iter(|| arena.queries_targets_par(&idxs, &idxs, false, &None));
+}
+
 benchmark_group!(
     simple,
     bench_rstarpt_construction,
@@ -233,7 +299,31 @@ benchmark_group!(
     bench_arena_query_geom,
     bench_arena_query_norm_geom,
     bench_all_to_all,
+    bench_all_to_all_max_dist,
     bench_arena_construction
 );
 
-benchmark_main!(simple, arena);
+benchmark_group!(
+    spatial,
+    bench_rstar_construction,
+    bench_kdtree_construction,
+    bench_grid_construction,
+    bench_field_construction,
+    bench_rstar_query,
+    bench_kdtree_query,
+    bench_grid_query,
+    bench_bruteforce_query,
+    bench_field_query,
+    bench_rstar_all_to_all,
+    bench_kdtree_all_to_all,
+    bench_grid_all_to_all,
+    bench_field_all_to_all,
+);
+
+#[cfg(feature = "parallel")]
+benchmark_group!(parallel, bench_all_to_all_par);
+
+#[cfg(feature = "parallel")]
+benchmark_main!(simple, arena, spatial, parallel);
+#[cfg(not(feature = "parallel"))]
+benchmark_main!(simple, arena, spatial);
@@ -0,0 +1,106 @@
+use std::fmt;
+
+use crate::NeuronIdx;
+
+/// Errors which can occur when constructing neurons, score functions and score matrices.
+#[derive(Debug)]
+pub enum Error {
+    /// Fewer points were given than are required, e.g. to calculate tangents.
+    TooFewPoints { required: usize, actual: usize },
+    /// Points and their tangents or alpha values have different lengths.
+    MismatchedLengths { points: usize, other: usize },
+    /// The point coordinate, tangent or alpha value at the given index is NaN or infinite.
+    NonFinite { idx: usize },
+    /// A target neuron must have at least one point.
+    EmptyTarget,
+    /// Tangent could not be calculated for the point at the given index.
+    DegenerateNeighborhood { idx: usize },
+    /// Score table has the wrong number of cells, unsorted thresholds, or could not be parsed.
+    MalformedTable(String),
+    /// Neuron index is not present in the arena.
+    MissingNeuron(NeuronIdx),
+    /// Another neuron in the arena already has this name.
+    DuplicateName(String),
+    /// Not enough neuron pairs or point matches to train a score matrix.
+    InsufficientTrainingData(&'static str),
+    /// A parameter is out of its valid range.
+    InvalidParameter(&'static str),
+    /// File contents could not be parsed.
+    Parse(String),
+    /// Error reading or writing a file.
+    Io(std::io::Error),
+    /// A thread pool for parallel queries could not be built.
+    #[cfg(feature = "parallel")]
+    ThreadPool(rayon::ThreadPoolBuildError),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::TooFewPoints { required, actual } => write!(
+                f,
+                "Too few points: {} required but {} given",
+                required, actual
+            ),
+            Error::MismatchedLengths { points, other } => write!(
+                f,
+                "Got {} points but {} tangents or alpha values",
+                points, other
+            ),
+            Error::NonFinite { idx } => write!(f, "Non-finite value at index {}", idx),
+            Error::EmptyTarget => write!(f, "Target neuron has no points"),
+            Error::DegenerateNeighborhood { idx } => {
+                write!(f, "Could not calculate tangent at index {}", idx)
+            }
+            Error::MalformedTable(msg) => write!(f, "Malformed score table: {}", msg),
+            Error::MissingNeuron(idx) => write!(f, "Neuron {} is not in the arena", idx),
+            Error::DuplicateName(name) => {
+                write!(f, "A neuron named {:?} is already in the arena", name)
+            }
+            Error::InsufficientTrainingData(msg) => {
+                write!(f, "Insufficient training data: {}", msg)
+            }
+            Error::InvalidParameter(msg) => write!(f, "Invalid parameter: {}", msg),
+            Error::Parse(msg) => write!(f, "Parse error: {}", msg),
+            Error::Io(e) => write!(f, "I/O error: {}", e),
+            #[cfg(feature = "parallel")]
+            Error::ThreadPool(e) => write!(f, "Could not build thread pool: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(e) => Some(e),
+            #[cfg(feature = "parallel")]
+            Error::ThreadPool(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(feature = "parallel")]
+impl From<rayon::ThreadPoolBuildError> for Error {
+    fn from(e: rayon::ThreadPoolBuildError) -> Self {
+        Error::ThreadPool(e)
+    }
+}
+
+impl From<csv::Error> for Error {
+    fn from(e: csv::Error) -> Self {
+        if !e.is_io_error() {
+            return Error::Parse(e.to_string());
+        }
+        match e.into_kind() {
+            csv::ErrorKind::Io(io_err) => Error::Io(io_err),
+            kind => Error::Parse(format!("{:?}", kind)),
+        }
+    }
+}
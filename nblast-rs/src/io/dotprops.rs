@@ -0,0 +1,216 @@
+//! CSV files of points, tangents and alpha values, as written by NAT's `dotprops`
+//! (e.g. `write.csv(as.data.frame(dp))`).
+//!
+//! The columns are `points.X`, `points.Y`, `points.Z`, `alpha`, `vect.1`, `vect.2`, `vect.3`,
+//! preceded by an unnamed row index column.
+//! Columns are found by name, so their order does not matter,
+//! and the index column is optional when reading.
+use std::io::{Read, Write};
+
+use csv::{ReaderBuilder, WriterBuilder};
+use nalgebra::base::{Unit, Vector3};
+
+use super::{find_col, parse_cell};
+use crate::{Error, Float, Precision, QueryNeuron, QueryPointTangents, RStarPointTangents};
+
+const POINT_COLS: [&str; 3] = ["points.X", "points.Y", "points.Z"];
+const ALPHA_COL: &str = "alpha";
+const VECT_COLS: [&str; 3] = ["vect.1", "vect.2", "vect.3"];
+const UNIT_TOLERANCE: Precision = 1e-9;
+
+/// Points, unit tangents and alpha values read from a dotprops CSV,
+/// in either precision.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dotprops<T: Float = Precision> {
+    pub points: Vec<[T; 3]>,
+    pub tangents: Vec<Unit<Vector3<T>>>,
+    pub alphas: Vec<T>,
+}
+
+impl<T: Float> Dotprops<T> {
+    /// Copy the points, tangents and alpha values of any neuron.
+    pub fn from_neuron(neuron: &impl QueryNeuron<T>) -> Self {
+        Self {
+            points: neuron.points(),
+            tangents: neuron.tangents(),
+            alphas: neuron.alphas(),
+        }
+    }
+
+    pub fn into_rstar(self) -> Result<RStarPointTangents<T>, Error> {
+        RStarPointTangents::new_with_tangents_alphas(self.points, self.tangents, self.alphas)
+    }
+
+    pub fn into_query(self) -> Result<QueryPointTangents<T>, Error> {
+        QueryPointTangents::new_with_tangents_alphas(self.points, self.tangents, self.alphas)
+    }
+}
+
+/// Tangents which are already unit length (as written by NAT or [write](fn.write.html))
+/// are kept as they are, so that reading and writing at full precision is lossless.
+fn to_unit<T: Float>(vect: Vector3<Precision>) -> Unit<Vector3<T>> {
+    let vect = if (vect.norm_squared() - 1.0).abs() < UNIT_TOLERANCE {
+        vect
+    } else {
+        vect.normalize()
+    };
+    Unit::new_unchecked(vect.map(T::from_precision))
+}
+
+/// Read a dotprops CSV.
+///
+/// Tangents which are not unit length are normalised.
+/// Values are not otherwise validated until they are converted into a neuron.
+pub fn read<R: Read, T: Float>(reader: R) -> Result<Dotprops<T>, Error> {
+    let mut reader = ReaderBuilder::new().has_headers(true).from_reader(reader);
+    let header = reader.headers()?.clone();
+    let mut point_idxs = [0; 3];
+    let mut vect_idxs = [0; 3];
+    for dim in 0..3 {
+        point_idxs[dim] = find_col(&header, POINT_COLS[dim])?;
+        vect_idxs[dim] = find_col(&header, VECT_COLS[dim])?;
+    }
+    let alpha_idx = find_col(&header, ALPHA_COL)?;
+
+    let mut out = Dotprops {
+        points: Vec::default(),
+        tangents: Vec::default(),
+        alphas: Vec::default(),
+    };
+    for (row, result) in reader.records().enumerate() {
+        let record = result?;
+        let mut point = [0.0; 3];
+        let mut vect = [0.0; 3];
+        for dim in 0..3 {
+            point[dim] = parse_cell(&record, point_idxs[dim], row)?;
+            vect[dim] = parse_cell(&record, vect_idxs[dim], row)?;
+        }
+        out.points.push([
+            T::from_precision(point[0]),
+            T::from_precision(point[1]),
+            T::from_precision(point[2]),
+        ]);
+        out.tangents
+            .push(to_unit(Vector3::new(vect[0], vect[1], vect[2])));
+        out.alphas
+            .push(T::from_precision(parse_cell(&record, alpha_idx, row)?));
+    }
+    Ok(out)
+}
+
+/// Read a dotprops CSV into a neuron which can be used as a target.
+pub fn read_rstar<R: Read, T: Float>(reader: R) -> Result<RStarPointTangents<T>, Error> {
+    read(reader)?.into_rstar()
+}
+
+/// Read a dotprops CSV into a neuron which can only be used as a query.
+pub fn read_query<R: Read, T: Float>(reader: R) -> Result<QueryPointTangents<T>, Error> {
+    read(reader)?.into_query()
+}
+
+/// Write a neuron's points, tangents and alpha values in the layout NAT writes,
+/// including the 1-based row index column.
+pub fn write<W: Write, T: Float>(writer: W, neuron: &impl QueryNeuron<T>) -> Result<(), Error> {
+    let mut writer = WriterBuilder::new().from_writer(writer);
+    let mut header = vec![""];
+    header.extend(POINT_COLS.iter());
+    header.push(ALPHA_COL);
+    header.extend(VECT_COLS.iter());
+    writer.write_record(&header)?;
+
+    for (idx, ((point, tangent), alpha)) in neuron
+        .points()
+        .iter()
+        .zip(neuron.tangents().iter())
+        .zip(neuron.alphas())
+        .enumerate()
+    {
+        let mut record = vec![(idx + 1).to_string()];
+        record.extend(point.iter().map(|v| v.to_string()));
+        record.push(alpha.to_string());
+        record.extend(tangent.iter().map(|v| v.to_string()));
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DistDot, TargetNeuron};
+
+    fn dotprops_path() -> std::path::PathBuf {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.pop();
+        path.push("data");
+        path.push("dotprops");
+        path.push("ChaMARCM-F000586_seg002.csv");
+        path
+    }
+
+    #[test]
+    fn read_nat() {
+        let f = std::fs::File::open(dotprops_path()).expect("file not found");
+        let dp: Dotprops = read(f).expect("Could not read dotprops");
+        assert_eq!(
+            dp.points[0],
+            [329.679962158203, 72.7188034057617, 31.0284690856934]
+        );
+        assert_eq!(dp.alphas[0], 0.844842871450449);
+        assert!((dp.tangents[0][0] - 0.978514075053433).abs() < 1e-9);
+        assert_eq!(dp.points.len(), dp.tangents.len());
+        assert_eq!(dp.points.len(), dp.alphas.len());
+
+        let neuron = dp.clone().into_rstar().expect("Could not construct neuron");
+        assert_eq!(neuron.alphas(), dp.alphas);
+        let DistDot { dist, dot } =
+            neuron.nearest_match_dist_dot(&dp.points[0], &dp.tangents[0], None, None);
+        assert_eq!(dist, 0.0);
+        assert!((dot - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let f = std::fs::File::open(dotprops_path()).expect("file not found");
+        let neuron: QueryPointTangents = read_query(f).expect("Could not read dotprops");
+        let mut buf = Vec::default();
+        write(&mut buf, &neuron).expect("Could not write dotprops");
+        assert!(buf.starts_with(b",points.X,points.Y,points.Z,alpha,vect.1,vect.2,vect.3\n1,"));
+        let neuron2 = read_query(buf.as_slice()).expect("Could not reread dotprops");
+        assert_eq!(
+            Dotprops::from_neuron(&neuron),
+            Dotprops::from_neuron(&neuron2)
+        );
+    }
+
+    #[test]
+    fn single_precision() {
+        let f = std::fs::File::open(dotprops_path()).expect("file not found");
+        let neuron: RStarPointTangents<f32> = read_rstar(f).expect("Could not read dotprops");
+        let f = std::fs::File::open(dotprops_path()).expect("file not found");
+        let dp: Dotprops = read(f).expect("Could not read dotprops");
+        assert_eq!(neuron.len(), dp.points.len());
+        assert_eq!(neuron.points()[0][0], dp.points[0][0] as f32);
+        for tangent in neuron.tangents() {
+            assert!((tangent.norm() - 1.0).abs() < 1e-6);
+        }
+
+        let mut buf = Vec::default();
+        write(&mut buf, &neuron).expect("Could not write dotprops");
+        let neuron2: QueryPointTangents<f32> =
+            read_query(buf.as_slice()).expect("Could not reread dotprops");
+        assert_eq!(neuron.points(), neuron2.points());
+        assert_eq!(neuron.alphas(), neuron2.alphas());
+    }
+
+    #[test]
+    fn missing_column() {
+        let no_alpha = "\"points.X\",\"points.Y\",\"points.Z\",\"vect.1\",\"vect.2\",\"vect.3\"\n\
+                        1,2,3,1,0,0\n";
+        match read::<_, Precision>(no_alpha.as_bytes()) {
+            Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Parse error"),
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! CSV files of paired landmarks, for fitting a [ThinPlateSpline](../../struct.ThinPlateSpline.html)
+//! between two spaces.
+//!
+//! The columns are `source.X`, `source.Y`, `source.Z`, `target.X`, `target.Y`, `target.Z`,
+//! with one landmark pair per row.
+//! Columns are found by name, so their order does not matter,
+//! and other columns (e.g. landmark names) are ignored.
+use std::io::{Read, Write};
+
+use csv::{ReaderBuilder, WriterBuilder};
+
+use super::{find_col, parse_cell};
+use crate::{Error, Precision, ThinPlateSpline};
+
+const SOURCE_COLS: [&str; 3] = ["source.X", "source.Y", "source.Z"];
+const TARGET_COLS: [&str; 3] = ["target.X", "target.Y", "target.Z"];
+
+/// Landmark locations in the source space and the corresponding target space.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Landmarks {
+    pub source: Vec<[Precision; 3]>,
+    pub target: Vec<[Precision; 3]>,
+}
+
+impl Landmarks {
+    /// Fit a spline which maps the source landmarks onto the target landmarks.
+    pub fn to_spline(&self) -> Result<ThinPlateSpline, Error> {
+        ThinPlateSpline::new(&self.source, &self.target)
+    }
+}
+
+/// Read a landmarks CSV.
+///
+/// Values are not validated until a spline is fitted.
+pub fn read<R: Read>(reader: R) -> Result<Landmarks, Error> {
+    let mut reader = ReaderBuilder::new().has_headers(true).from_reader(reader);
+    let header = reader.headers()?.clone();
+    let mut source_idxs = [0; 3];
+    let mut target_idxs = [0; 3];
+    for dim in 0..3 {
+        source_idxs[dim] = find_col(&header, SOURCE_COLS[dim])?;
+        target_idxs[dim] = find_col(&header, TARGET_COLS[dim])?;
+    }
+
+    let mut out = Landmarks::default();
+    for (row, result) in reader.records().enumerate() {
+        let record = result?;
+        let mut source = [0.0; 3];
+        let mut target = [0.0; 3];
+        for dim in 0..3 {
+            source[dim] = parse_cell(&record, source_idxs[dim], row)?;
+            target[dim] = parse_cell(&record, target_idxs[dim], row)?;
+        }
+        out.source.push(source);
+        out.target.push(target);
+    }
+    Ok(out)
+}
+
+/// Read a landmarks CSV and fit a spline to it.
+pub fn read_spline<R: Read>(reader: R) -> Result<ThinPlateSpline, Error> {
+    read(reader)?.to_spline()
+}
+
+/// Write landmark pairs, which must be the same length.
+pub fn write<W: Write>(writer: W, landmarks: &Landmarks) -> Result<(), Error> {
+    if landmarks.source.len() != landmarks.target.len() {
+        return Err(Error::MismatchedLengths {
+            points: landmarks.source.len(),
+            other: landmarks.target.len(),
+        });
+    }
+    let mut writer = WriterBuilder::new().from_writer(writer);
+    let mut header = SOURCE_COLS.to_vec();
+    header.extend(TARGET_COLS.iter());
+    writer.write_record(&header)?;
+
+    for (source, target) in landmarks.source.iter().zip(landmarks.target.iter()) {
+        let record: Vec<_> = source
+            .iter()
+            .chain(target.iter())
+            .map(|v| v.to_string())
+            .collect();
+        writer.write_record(&record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_named() {
+        let csv = "name,target.X,target.Y,target.Z,source.X,source.Y,source.Z\n\
+                   a,1,2,3,0,0,0\n\
+                   b,11,2,3,10,0,0\n\
+                   c,1,12,3,0,10,0\n\
+                   d,1,2,13,0,0,10\n";
+        let landmarks = read(csv.as_bytes()).expect("Could not read landmarks");
+        assert_eq!(landmarks.source[1], [10.0, 0.0, 0.0]);
+        assert_eq!(landmarks.target[1], [11.0, 2.0, 3.0]);
+
+        let spline = landmarks.to_spline().expect("Could not fit spline");
+        let warped = spline.transform_point(&[5.0, 5.0, 5.0]);
+        for (w, e) in warped.iter().zip([6.0, 7.0, 8.0].iter()) {
+            assert!((w - e).abs() < 1e-9);
+        }
+    }
+
+    #[test]
+    fn roundtrip() {
+        let landmarks = Landmarks {
+            source: vec![[0.5, 1.0, 2.0], [3.0, 4.0, 5.25]],
+            target: vec![[-1.0, 0.0, 1.0], [1e6, 2.0, 3.0]],
+        };
+        let mut buf = Vec::default();
+        write(&mut buf, &landmarks).expect("Could not write landmarks");
+        assert!(buf.starts_with(b"source.X,source.Y,source.Z,target.X,target.Y,target.Z\n"));
+        assert_eq!(
+            read(buf.as_slice()).expect("Could not reread landmarks"),
+            landmarks
+        );
+    }
+
+    #[test]
+    fn missing_column() {
+        let no_target_z = "source.X,source.Y,source.Z,target.X,target.Y\n1,2,3,4,5\n";
+        match read(no_target_z.as_bytes()) {
+            Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Parse error"),
+        }
+    }
+}
@@ -0,0 +1,23 @@
+//! Reading and writing neurons in formats used by other tools.
+use csv::StringRecord;
+
+use crate::{Error, Precision};
+
+pub mod dotprops;
+pub mod landmarks;
+pub mod swc;
+
+/// Index of the CSV column with the given name.
+fn find_col(header: &StringRecord, name: &str) -> Result<usize, Error> {
+    header
+        .iter()
+        .position(|h| h.trim() == name)
+        .ok_or_else(|| Error::Parse(format!("No {:?} column", name)))
+}
+
+/// Parse the number in the given column of a CSV record.
+fn parse_cell(record: &StringRecord, col: usize, row: usize) -> Result<Precision, Error> {
+    let cell = record.get(col).unwrap_or_default().trim();
+    cell.parse::<Precision>()
+        .map_err(|_| Error::Parse(format!("Row {}: {:?} is not a number", row, cell)))
+}
@@ -0,0 +1,342 @@
+//! Skeletons in the [SWC](http://www.neuronland.org/NLMorphologyConverter/MorphologyFormats/SWC/Spec.html)
+//! format, as exported by most tracing tools,
+//! and resampling them into point clouds for NBLAST.
+//!
+//! Each line describes one node as whitespace-separated
+//! `id type x y z radius parent`, where the parent of a root is negative.
+//! Anything after a `#` is a comment.
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Read};
+
+use crate::{Error, Precision, QueryPointTangents, RStarPointTangents};
+
+/// Resampled points, and the index of each point's parent (`None` for roots).
+pub type ResampledTree = (Vec<[Precision; 3]>, Vec<Option<usize>>);
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct SwcNode {
+    pub id: usize,
+    /// Structure identifier, e.g. 1 for soma, 2 for axon, 3 for dendrite.
+    pub structure: i64,
+    pub position: [Precision; 3],
+    pub radius: Precision,
+    /// `None` for root nodes.
+    pub parent: Option<usize>,
+}
+
+/// A skeleton of one or more trees.
+///
+/// Every node's parent is present and every node is reachable from a root.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Swc {
+    nodes: Vec<SwcNode>,
+}
+
+fn parse_field<T: std::str::FromStr>(field: &str, line_no: usize) -> Result<T, Error> {
+    field
+        .parse()
+        .map_err(|_| Error::Parse(format!("Line {}: could not parse {:?}", line_no, field)))
+}
+
+fn parse_line(line: &str, line_no: usize) -> Result<Option<SwcNode>, Error> {
+    let content = line.split('#').next().unwrap_or_default();
+    let fields: Vec<_> = content.split_whitespace().collect();
+    if fields.is_empty() {
+        return Ok(None);
+    }
+    if fields.len() < 7 {
+        return Err(Error::Parse(format!(
+            "Line {}: expected 7 fields, got {}",
+            line_no,
+            fields.len()
+        )));
+    }
+    let parent: i64 = parse_field(fields[6], line_no)?;
+    Ok(Some(SwcNode {
+        id: parse_field(fields[0], line_no)?,
+        structure: parse_field(fields[1], line_no)?,
+        position: [
+            parse_field(fields[2], line_no)?,
+            parse_field(fields[3], line_no)?,
+            parse_field(fields[4], line_no)?,
+        ],
+        radius: parse_field(fields[5], line_no)?,
+        parent: if parent < 0 {
+            None
+        } else {
+            Some(parent as usize)
+        },
+    }))
+}
+
+/// Read an SWC file.
+pub fn read<R: Read>(reader: R) -> Result<Swc, Error> {
+    let mut nodes = Vec::default();
+    for (idx, line) in BufReader::new(reader).lines().enumerate() {
+        if let Some(node) = parse_line(&line?, idx + 1)? {
+            nodes.push(node);
+        }
+    }
+    Swc::new(nodes)
+}
+
+impl Swc {
+    /// Fails if node IDs are not unique, a parent is missing,
+    /// or there is a cycle.
+    pub fn new(nodes: Vec<SwcNode>) -> Result<Self, Error> {
+        let mut id_to_idx = HashMap::with_capacity(nodes.len());
+        for (idx, node) in nodes.iter().enumerate() {
+            if id_to_idx.insert(node.id, idx).is_some() {
+                return Err(Error::Parse(format!("Duplicate node ID {}", node.id)));
+            }
+        }
+        if let Some(node) = nodes.iter().find(|n| match n.parent {
+            Some(p) => !id_to_idx.contains_key(&p),
+            None => false,
+        }) {
+            return Err(Error::Parse(format!(
+                "Parent of node {} is missing",
+                node.id
+            )));
+        }
+        let out = Self { nodes };
+        if out.visit_order().len() != out.nodes.len() {
+            return Err(Error::Parse("Skeleton contains a cycle".to_owned()));
+        }
+        Ok(out)
+    }
+
+    pub fn nodes(&self) -> &[SwcNode] {
+        &self.nodes
+    }
+
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Indices of nodes without a parent.
+    /// There may be several, e.g. for a fragmented tracing.
+    pub fn roots(&self) -> impl Iterator<Item = usize> + '_ {
+        self.nodes
+            .iter()
+            .enumerate()
+            .filter(|(_, n)| n.parent.is_none())
+            .map(|(idx, _)| idx)
+    }
+
+    /// Multiply all positions and radii by `factor`,
+    /// e.g. 0.001 to convert nanometres to microns.
+    pub fn scale(&mut self, factor: Precision) {
+        for node in self.nodes.iter_mut() {
+            for v in node.position.iter_mut() {
+                *v *= factor;
+            }
+            node.radius *= factor;
+        }
+    }
+
+    /// Indices of each node's children.
+    fn children(&self) -> Vec<Vec<usize>> {
+        let id_to_idx: HashMap<_, _> = self
+            .nodes
+            .iter()
+            .enumerate()
+            .map(|(idx, n)| (n.id, idx))
+            .collect();
+        let mut out = vec![Vec::default(); self.nodes.len()];
+        for (idx, node) in self.nodes.iter().enumerate() {
+            if let Some(p_idx) = node.parent.and_then(|p| id_to_idx.get(&p)) {
+                out[*p_idx].push(idx);
+            }
+        }
+        out
+    }
+
+    /// Node indices in depth-first order from each root.
+    /// Nodes in cycles are not visited.
+    fn visit_order(&self) -> Vec<usize> {
+        let children = self.children();
+        let mut out = Vec::with_capacity(self.nodes.len());
+        let mut to_visit: Vec<_> = self.roots().collect();
+        while let Some(idx) = to_visit.pop() {
+            out.push(idx);
+            to_visit.extend(children[idx].iter());
+        }
+        out
+    }
+
+    /// Place points at every `step` along the cable of each tree, starting at its root.
+    ///
+    /// Points are spaced by cable length rather than by straight-line distance,
+    /// so branches each continue the spacing from their branch point.
+    pub fn resample(&self, step: Precision) -> Result<Vec<[Precision; 3]>, Error> {
+        self.resample_with_parents(step).map(|(points, _)| points)
+    }
+
+    /// As [resample](#method.resample), also returning the index of each point's parent
+    /// in the resampled tree (`None` for roots).
+    pub fn resample_with_parents(&self, step: Precision) -> Result<ResampledTree, Error> {
+        if step <= 0.0 || !step.is_finite() {
+            return Err(Error::InvalidParameter(
+                "Resampling step must be positive and finite",
+            ));
+        }
+        let children = self.children();
+        let mut out = Vec::default();
+        let mut parents = Vec::default();
+        // (node index, cable length since the last point, index of the last point)
+        let mut to_visit = Vec::default();
+        for root in self.roots() {
+            to_visit.push((root, 0.0, out.len()));
+            out.push(self.nodes[root].position);
+            parents.push(None);
+        }
+        while let Some((idx, since_last, last_point)) = to_visit.pop() {
+            let start = self.nodes[idx].position;
+            for child in children[idx].iter() {
+                let end = self.nodes[*child].position;
+                let diff = [end[0] - start[0], end[1] - start[1], end[2] - start[2]];
+                let length = diff.iter().map(|d| d * d).sum::<Precision>().sqrt();
+
+                let mut along = step - since_last;
+                let mut last = -since_last;
+                let mut parent = last_point;
+                while along <= length {
+                    let frac = along / length;
+                    parents.push(Some(parent));
+                    parent = out.len();
+                    out.push([
+                        start[0] + diff[0] * frac,
+                        start[1] + diff[1] * frac,
+                        start[2] + diff[2] * frac,
+                    ]);
+                    last = along;
+                    along += step;
+                }
+                to_visit.push((*child, length - last, parent));
+            }
+        }
+        Ok((out, parents))
+    }
+
+    /// Resample at `step` and calculate tangents from the `k` nearest neighbors of each point,
+    /// for use as a target (e.g. in an [NblastArena](../../struct.NblastArena.html)).
+    pub fn to_rstar(&self, step: Precision, k: usize) -> Result<RStarPointTangents, Error> {
+        RStarPointTangents::new(self.resample(step)?, k)
+    }
+
+    /// As [to_rstar](#method.to_rstar), for use only as a query.
+    pub fn to_query(&self, step: Precision, k: usize) -> Result<QueryPointTangents, Error> {
+        QueryPointTangents::new(self.resample(step)?, k)
+    }
+
+    /// Resample at `step` and calculate tangents from the skeleton's edges.
+    /// See [IndexedPointTangents::new_with_parents](../../struct.IndexedPointTangents.html#method.new_with_parents).
+    pub fn to_rstar_with_parents(&self, step: Precision) -> Result<RStarPointTangents, Error> {
+        let (points, parents) = self.resample_with_parents(step)?;
+        RStarPointTangents::new_with_parents(points, &parents)
+    }
+
+    /// As [to_rstar_with_parents](#method.to_rstar_with_parents), for use only as a query.
+    pub fn to_query_with_parents(&self, step: Precision) -> Result<QueryPointTangents, Error> {
+        let (points, parents) = self.resample_with_parents(step)?;
+        QueryPointTangents::new_with_parents(points, &parents)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::QueryNeuron;
+
+    const SWC: &str = "# a comment
+# id type x y z radius parent
+1 1 0 0 0 1.0 -1
+2 3 10 0 0 0.5 1   # trailing comment
+
+3 3 10 5 0 0.5 2
+4 3 10 0 4 0.5 2
+10 3 100 100 100 0.5 -1
+11 3 100 100 103 0.5 10
+";
+
+    #[test]
+    fn read_swc() {
+        let swc = read(SWC.as_bytes()).expect("Could not read SWC");
+        assert_eq!(swc.len(), 6);
+        assert_eq!(swc.roots().count(), 2);
+        assert_eq!(
+            swc.nodes()[1],
+            SwcNode {
+                id: 2,
+                structure: 3,
+                position: [10.0, 0.0, 0.0],
+                radius: 0.5,
+                parent: Some(1),
+            }
+        );
+    }
+
+    #[test]
+    fn invalid_swc() {
+        let missing_parent = "1 1 0 0 0 1 -1\n2 1 0 0 1 1 5\n";
+        assert!(read(missing_parent.as_bytes()).is_err());
+        let duplicate = "1 1 0 0 0 1 -1\n1 1 0 0 1 1 -1\n";
+        assert!(read(duplicate.as_bytes()).is_err());
+        let cycle = "1 1 0 0 0 1 -1\n2 1 0 0 1 1 3\n3 1 0 0 2 1 2\n";
+        assert!(read(cycle.as_bytes()).is_err());
+        let short = "1 1 0 0 0 -1\n";
+        match read(short.as_bytes()) {
+            Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Parse error"),
+        }
+    }
+
+    #[test]
+    fn resample() {
+        let swc = read(SWC.as_bytes()).expect("Could not read SWC");
+        let points = swc.resample(2.0).expect("Could not resample");
+        // 2 roots; 5 on the first edge; 2 and 2 on the branches,
+        // continuing spacing from the branch point; 1 on the second tree
+        assert_eq!(points.len(), 2 + 5 + 2 + 2 + 1);
+        assert!(points.contains(&[10.0, 4.0, 0.0]));
+        assert!(points.contains(&[100.0, 100.0, 102.0]));
+        assert!(swc.resample(0.0).is_err());
+
+        let (points2, parents) = swc.resample_with_parents(2.0).expect("Could not resample");
+        assert_eq!(points, points2);
+        assert_eq!(parents.iter().filter(|p| p.is_none()).count(), 2);
+        // every non-root point is one step along the cable from its parent
+        let branch_point = [10.0, 0.0, 0.0];
+        for (point, parent) in points.iter().zip(parents.iter()) {
+            if let Some(p_idx) = parent {
+                let parent_point = points[*p_idx];
+                let dist: Precision = point
+                    .iter()
+                    .zip(parent_point.iter())
+                    .map(|(a, b)| (a - b) * (a - b))
+                    .sum::<Precision>()
+                    .sqrt();
+                assert!((dist - 2.0).abs() < 1e-9 || parent_point == branch_point);
+            }
+        }
+        let neuron = swc
+            .to_rstar_with_parents(2.0)
+            .expect("Could not make dotprops");
+        assert_eq!(neuron.len(), points.len());
+    }
+
+    #[test]
+    fn scale_and_dotprops() {
+        let mut swc = read(SWC.as_bytes()).expect("Could not read SWC");
+        swc.scale(0.5);
+        assert_eq!(swc.nodes()[1].position, [5.0, 0.0, 0.0]);
+        assert_eq!(swc.nodes()[1].radius, 0.25);
+        let neuron = swc.to_rstar(0.5, 5).expect("Could not make dotprops");
+        assert_eq!(neuron.len(), swc.resample(0.5).unwrap().len());
+    }
+}
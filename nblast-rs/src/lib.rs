@@ -14,6 +14,8 @@
 //!   - Find the nearest point in `T`, and its associated tangent
 //!   - Compute the distance between the two points
 //!   - Compute the absolute dot product of the two tangents
+//!     - Optionally, scale it by the geometric mean of the two points' alpha values
+//!       (see below)
 //!   - Apply some empirically-derived function to the (distance, dot_product) tuple
 //!     - As published, this is the log probabity ratio of any pair belonging to closely related or unrelated neurons
 //! - Repeat for all points, summing the results
@@ -22,6 +24,12 @@
 //! it is highly dependent on the size of the point cloud
 //! and is not commutative, i.e. `f(Q, T) != f(T, Q)`.
 //!
+//! Each point also has an "alpha" value, describing how colinear its neighborhood is:
+//! this is 1 for points on a straight line, and closer to 0 for points in noisy, blob-like regions.
+//! Weighting point matches by alpha
+//! (equivalent to NAT's `UseAlpha=TRUE`)
+//! reduces the contribution of such regions.
+//!
 //! To make queries between two pairs of neurons comparable,
 //! the result can be normalized by the "self-hit" score of the query, i.e. `f(Q, Q)`.
 //!
@@ -40,27 +48,152 @@
 //! All `TargetNeuron`s are also `QueryNeuron`s.
 //!
 //! [QueryPointTangents](struct.QueryPointTangents.html) and
-//! [RStarPointTangents](struct.RStarPointTangents.html) implement these, respectively.
-//! Both can be created with pre-calculated tangents, or calculate them on instantiation.
+//! [IndexedPointTangents](struct.IndexedPointTangents.html) implement these, respectively.
+//! Both can be created with pre-calculated tangents, or calculate them on instantiation,
+//! either from a [Neighborhood](enum.Neighborhood.html) of nearby points
+//! or from the edges of a skeleton.
+//! `IndexedPointTangents` can use any of the [spatial](spatial/index.html) indices
+//! to find nearest neighbors, with the same constructors:
+//! [RStarPointTangents](type.RStarPointTangents.html) is a good default.
 //!
 //! The [NblastArena](struct.NblastArena.html) contains a collection of `TargetNeuron`s
 //! and a function to apply to pointwise (distance, absolute dot product) pairs to generate
 //! a score for that point match, for convenient many-to-many comparisons.
-//! A pre-calculated table of point match scores can be converted into a function with [table_to_fn](fn.table_to_fn.html).
+//! Neurons in the arena can be given names and key/value metadata,
+//! to look them up and restrict queries to targets of e.g. a given cell type.
+//! Nearest-neighbor searches can be cut off at the distance beyond which
+//! the score function no longer depends on distance,
+//! which makes comparing distant neurons much faster.
+//! Arenas can be saved to a binary file and loaded again
+//! without recalculating tangents or self-hit scores;
+//! see [NblastArena::save](struct.NblastArena.html#method.save).
+//! Fallible operations return an [Error](enum.Error.html).
+//!
+//! A pre-calculated table of point match scores can be converted into a function with [table_to_fn](fn.table_to_fn.html),
+//! or [table_to_interp_fn](fn.table_to_interp_fn.html) to interpolate between bins.
+//! Such tables can be read, written, and validated as a [ScoreMatrix](struct.ScoreMatrix.html),
+//! or trained from neurons known to be related or unrelated
+//! with the [ScoreMatrixBuilder](struct.ScoreMatrixBuilder.html).
+//! Where no such table exists, an analytic [ParametricScore](struct.ParametricScore.html)
+//! can be used instead.
+//!
+//! Neurons can be read from and written to files with the [io](io/index.html) module,
+//! and moved between hemispheres or registrations with an affine
+//! [Transform](struct.Transform.html) or a landmark-based
+//! [ThinPlateSpline](struct.ThinPlateSpline.html).
+//! Small registration offsets can be corrected before scoring
+//! with a rigid [Icp](struct.Icp.html) alignment.
+//!
+//! Neurons, score functions and arenas are generic over their [Float](trait.Float.html) type,
+//! defaulting to `f64`.
+//! Using `f32` halves the memory needed for large arenas;
+//! score tables can be converted to functions of either precision.
+//!
+//! # Features
+//!
+//! - `parallel`: adds methods to the `NblastArena` for making many queries
+//!   across a [rayon](https://docs.rs/rayon) thread pool.
 use nalgebra::base::{Matrix3, Unit, Vector3};
+use nalgebra::{ComplexField, RealField};
 use rstar::primitives::PointWithData;
-use rstar::RTree;
-use std::collections::HashMap;
+use rstar::{RTree, RTreeNum};
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+
+#[cfg(feature = "parallel")]
+use rayon::prelude::*;
+#[cfg(feature = "parallel")]
+use std::collections::HashSet;
+#[cfg(feature = "parallel")]
+use std::sync::Arc;
 
 pub use nalgebra;
 
+mod error;
+pub use error::Error;
+
+pub mod io;
+
+mod parametric;
+pub use parametric::{DistKernel, ParametricScore};
+
+mod smat;
+pub use smat::{ScoreMatrix, ScoreMatrixBuilder};
+
+mod persist;
+pub use persist::ScoreFnRecord;
+
+pub mod spatial;
+use spatial::{BruteForce, DistanceField, GridHash, KdTree, RStarIndex, SpatialIndex};
+
+mod transform;
+pub use transform::{Icp, ThinPlateSpline, Transform};
+
+#[cfg(test)]
+mod test_utils;
+
 // NOTE: will panic if this is changed due to use of Matrix3x5
 // const N_NEIGHBORS: usize = 5;
 
-/// Floating point precision type used internally
+/// Default floating point precision type,
+/// and the precision of score tables and file I/O.
 pub type Precision = f64;
 
-type PointWithIndex = PointWithData<usize, [Precision; 3]>;
+/// Floating point types which neurons can be stored and compared in:
+/// `f32` halves the memory and can be faster,
+/// and is precise enough for micron-scale coordinates.
+pub trait Float: RealField + RTreeNum {
+    fn from_precision(value: Precision) -> Self;
+    fn to_precision(self) -> Precision;
+    fn is_nan(self) -> bool;
+
+    /// Spread of a tangent neighborhood, relative to the magnitude of its coordinates,
+    /// below which its points are considered coincident.
+    ///
+    /// This is `1e-9` for `f64`, the tolerance used before neurons were generic,
+    /// so that existing `f64` results are unchanged;
+    /// and `1e-6` for `f32`, a few times its machine epsilon (about `1.2e-7`),
+    /// so that rounding error is not mistaken for spread.
+    fn degenerate_tolerance() -> Self;
+}
+
+impl Float for f32 {
+    fn from_precision(value: Precision) -> Self {
+        value as f32
+    }
+
+    fn to_precision(self) -> Precision {
+        Precision::from(self)
+    }
+
+    fn is_nan(self) -> bool {
+        f32::is_nan(self)
+    }
+
+    fn degenerate_tolerance() -> Self {
+        1e-6
+    }
+}
+
+impl Float for f64 {
+    fn from_precision(value: Precision) -> Self {
+        value
+    }
+
+    fn to_precision(self) -> Precision {
+        self
+    }
+
+    fn is_nan(self) -> bool {
+        f64::is_nan(self)
+    }
+
+    fn degenerate_tolerance() -> Self {
+        1e-9
+    }
+}
+
+type PointWithIndex<T> = PointWithData<usize, [T; 3]>;
 
 /// Enumeration of methods to ensure that queries are symmetric/ commutative
 /// (i.e. f(q, t) = f(t, q)).
@@ -76,19 +209,18 @@ pub enum Symmetry {
     Max,
 }
 
-fn apply_symmetry(
-    symmetry: &Symmetry,
-    query_score: Precision,
-    target_score: Precision,
-) -> Precision {
+fn apply_symmetry<T: Float>(symmetry: &Symmetry, query_score: T, target_score: T) -> T {
+    let two = T::from_precision(2.0);
     match symmetry {
-        Symmetry::ArithmeticMean => (query_score + target_score) / 2.0,
-        Symmetry::GeometricMean => (query_score.max(0.0) * target_score.max(0.0)).sqrt(),
+        Symmetry::ArithmeticMean => (query_score + target_score) / two,
+        Symmetry::GeometricMean => {
+            (query_score.max(T::zero()) * target_score.max(T::zero())).sqrt()
+        }
         Symmetry::HarmonicMean => {
-            if query_score.max(0.0) * target_score.max(0.0) == 0.0 {
-                0.0
+            if query_score.max(T::zero()) * target_score.max(T::zero()) == T::zero() {
+                T::zero()
             } else {
-                2.0 / (1.0 / query_score + 1.0 / target_score)
+                two / (T::one() / query_score + T::one() / target_score)
             }
         }
         Symmetry::Min => query_score.min(target_score),
@@ -100,25 +232,101 @@ fn apply_symmetry(
 /// Contains the Euclidean distance between the points,
 /// and the absolute dot product of the (unit) tangents,
 /// i.e. the absolute cosine of the angle between them.
-#[derive(Debug, Clone, Copy)]
-pub struct DistDot {
-    pub dist: Precision,
-    pub dot: Precision,
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DistDot<T = Precision> {
+    pub dist: T,
+    pub dot: T,
+}
+
+impl<T: Float> DistDot<T> {
+    /// Returned instead of a point match when the target has no point
+    /// within the maximum search distance:
+    /// the distance is the largest representable value, and the dot product is 0.
+    pub fn out_of_range() -> Self {
+        Self {
+            dist: T::max_value(),
+            dot: T::zero(),
+        }
+    }
+
+    /// Replace a NaN distance with the largest representable value, and a NaN dot product with 0,
+    /// so that looking them up in a table never scores them as a good match.
+    fn nan_to_worst(&self) -> Self {
+        Self {
+            dist: if self.dist.is_nan() {
+                T::max_value()
+            } else {
+                self.dist
+            },
+            dot: if self.dot.is_nan() {
+                T::zero()
+            } else {
+                self.dot
+            },
+        }
+    }
 }
 
-impl Default for DistDot {
+impl<T: Float> Default for DistDot<T> {
     fn default() -> Self {
         Self {
-            dist: 0.0,
-            dot: 1.0,
+            dist: T::zero(),
+            dot: T::one(),
         }
     }
 }
 
+/// Details of how a single query point matched its target.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PointMatch<T = Precision> {
+    /// Index of the query point, consistent with the query's [points](trait.QueryNeuron.html#tymethod.points).
+    pub query_idx: usize,
+    /// Index of the nearest target point, consistent with the target's [points](trait.QueryNeuron.html#tymethod.points).
+    pub target_idx: usize,
+    pub dist_dot: DistDot<T>,
+    /// The output of the score function for this point match.
+    pub score: T,
+}
+
+/// Result of a query which stops early if it cannot reach a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BoundedScore<T = Precision> {
+    /// The exact score, which is at least the threshold.
+    Exact(T),
+    /// The score is known to be below the threshold.
+    BelowThreshold,
+}
+
+/// Sum point match scores, stopping as soon as the total cannot reach the threshold
+/// even if every remaining point scores `max_point_score`.
+fn sum_bounded<T: Float>(
+    scores: impl Iterator<Item = T>,
+    len: usize,
+    threshold: T,
+    max_point_score: T,
+) -> BoundedScore<T> {
+    let mut score_total = T::zero();
+    for (idx, score) in scores.enumerate() {
+        score_total += score;
+        let remaining = T::from_precision((len - idx - 1) as f64);
+        if score_total + remaining * max_point_score < threshold {
+            return BoundedScore::BelowThreshold;
+        }
+    }
+    if score_total < threshold {
+        BoundedScore::BelowThreshold
+    } else {
+        BoundedScore::Exact(score_total)
+    }
+}
+
 /// Trait for objects which can be used as queries
 /// (not necessarily as targets) with NBLAST.
 /// See [TargetNeuron](trait.TargetNeuron.html).
-pub trait QueryNeuron {
+///
+/// `T` is the floating point type of the neuron's points, tangents and alpha values,
+/// and of scores calculated with it.
+pub trait QueryNeuron<T: Float = Precision> {
     /// Number of points in the neuron.
     fn len(&self) -> usize;
 
@@ -130,75 +338,127 @@ pub trait QueryNeuron {
     /// Calculate the raw NBLAST score by comparing this neuron to
     /// the given target neuron, using the given score function.
     /// The score function is applied to each point match distance and summed.
+    /// If `use_alpha` is true, the absolute dot product of each point match
+    /// is scaled by the geometric mean of the two points' alpha values.
     fn query(
         &self,
-        target: &impl TargetNeuron,
-        score_fn: &impl Fn(&DistDot) -> Precision,
-    ) -> Precision;
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> T;
+
+    /// As [query](#tymethod.query), but stop early if the raw score cannot reach `threshold`.
+    /// `max_point_score` must be at least the highest value the score function can return,
+    /// e.g. the maximum cell of a score table.
+    ///
+    /// The default implementation calculates the full score and compares it to the threshold.
+    fn query_bounded(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+        threshold: T,
+        _max_point_score: T,
+    ) -> BoundedScore<T> {
+        let score = self.query(target, use_alpha, score_fn);
+        if score < threshold {
+            BoundedScore::BelowThreshold
+        } else {
+            BoundedScore::Exact(score)
+        }
+    }
+
+    /// As [query](#tymethod.query), but return the details of every point match
+    /// rather than their summed score, in the order of the query's [points](#tymethod.points).
+    fn query_matches(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> Vec<PointMatch<T>>;
 
     /// The raw NBLAST score if this neuron was compared with itself using the given score function.
     /// Used for normalisation.
-    fn self_hit(&self, score_fn: &impl Fn(&DistDot) -> Precision) -> Precision {
-        score_fn(&DistDot::default()) * self.len() as Precision
+    fn self_hit(&self, use_alpha: bool, score_fn: &impl Fn(&DistDot<T>) -> T) -> T {
+        if use_alpha {
+            self.alphas().into_iter().fold(T::zero(), |sum, alpha| {
+                sum + score_fn(&DistDot {
+                    dist: T::zero(),
+                    dot: alpha,
+                })
+            })
+        } else {
+            score_fn(&DistDot::default()) * T::from_precision(self.len() as f64)
+        }
     }
 
     /// Return an owned copy of the points present in the neuron.
     /// The order is not guaranteed, but is consistent with
     /// [tangents](#method.tangents).
-    fn points(&self) -> Vec<[Precision; 3]>;
+    fn points(&self) -> Vec<[T; 3]>;
 
     /// Return an owned copy of the unit tangents present in the neuron.
     /// The order is not guaranteed, but is consistent with
     /// [points](#method.points).
-    fn tangents(&self) -> Vec<Unit<Vector3<Precision>>>;
+    fn tangents(&self) -> Vec<Unit<Vector3<T>>>;
+
+    /// Return an owned copy of the alpha values present in the neuron.
+    /// The order is not guaranteed, but is consistent with
+    /// [points](#method.points).
+    fn alphas(&self) -> Vec<T>;
 }
 
 /// Minimal struct to use as the query (not the target) of an NBLAST
 /// comparison.
 #[derive(Clone)]
-pub struct QueryPointTangents {
+pub struct QueryPointTangents<T: Float = Precision> {
     /// Locations of points in point cloud.
-    points: Vec<[Precision; 3]>,
+    points: Vec<[T; 3]>,
     /// Unit-length tangent vectors for each point in the cloud.
-    tangents: Vec<Unit<Vector3<Precision>>>,
+    tangents: Vec<Unit<Vector3<T>>>,
+    /// Colinearity of each point's neighborhood.
+    alphas: Vec<T>,
 }
 
-fn subtract_points(p1: &[Precision; 3], p2: &[Precision; 3]) -> [Precision; 3] {
-    let mut result = [0.0; 3];
+fn subtract_points<T: Float>(p1: &[T; 3], p2: &[T; 3]) -> [T; 3] {
+    let mut result = [T::zero(); 3];
     for ((rref, v1), v2) in result.iter_mut().zip(p1).zip(p2) {
-        *rref = v1 - v2;
+        *rref = *v1 - *v2;
     }
     result
 }
 
-fn center_points<'a>(
-    points: impl Iterator<Item = &'a [Precision; 3]>,
-) -> impl Iterator<Item = [Precision; 3]> {
+fn center_points<'a, T: Float>(
+    points: impl Iterator<Item = &'a [T; 3]>,
+) -> impl Iterator<Item = [T; 3]> {
     let mut points_vec = Vec::default();
-    let mut means: [Precision; 3] = [0.0, 0.0, 0.0];
+    let mut means: [T; 3] = [T::zero(); 3];
     for pt in points {
         points_vec.push(*pt);
         for (sum, v) in means.iter_mut().zip(pt.iter()) {
-            *sum += v;
+            *sum += *v;
         }
     }
 
+    let len = T::from_precision(points_vec.len() as f64);
     for val in means.iter_mut() {
-        *val /= points_vec.len() as Precision;
+        *val /= len;
     }
     let subtract = move |p| subtract_points(&p, &means);
     points_vec.into_iter().map(subtract)
 }
 
-fn dot(a: &[Precision], b: &[Precision]) -> Precision {
-    a.iter().zip(b.iter()).fold(0.0, |sum, (ax, bx)| sum + ax * bx)
+fn dot<T: Float>(a: &[T], b: &[T]) -> T {
+    a.iter()
+        .zip(b.iter())
+        .fold(T::zero(), |sum, (ax, bx)| sum + *ax * *bx)
 }
 
 /// Calculate inertia from iterator of points.
 /// This is an implementation of matrix * matrix.transpose(),
 /// to sidestep the fixed-size constraints of linalg's built-in classes.
 /// Only calculates the lower triangle and diagonal.
-fn calc_inertia<'a>(points: impl Iterator<Item = &'a [Precision; 3]>) -> Matrix3<Precision> {
+fn calc_inertia<'a, T: Float>(points: impl Iterator<Item = &'a [T; 3]>) -> Matrix3<T> {
     let mut xs = Vec::default();
     let mut ys = Vec::default();
     let mut zs = Vec::default();
@@ -208,26 +468,60 @@ fn calc_inertia<'a>(points: impl Iterator<Item = &'a [Precision; 3]>) -> Matrix3
         zs.push(point[2]);
     }
     Matrix3::new(
-        dot(&xs, &xs), 0.0, 0.0,
-        dot(&ys, &xs), dot(&ys, &ys), 0.0,
+        dot(&xs, &xs), T::zero(), T::zero(),
+        dot(&ys, &xs), dot(&ys, &ys), T::zero(),
         dot(&zs, &xs), dot(&zs, &ys), dot(&zs, &zs),
     )
 }
 
-fn points_to_tangent_eig<'a>(
-    points: impl Iterator<Item = &'a [Precision; 3]>,
-) -> Option<Unit<Vector3<Precision>>> {
+/// Calculate the alpha value (colinearity) of a neighborhood from the
+/// eigenvalues of its inertia matrix, as `(e1 - e2) / (e1 + e2 + e3)`
+/// where `e1 >= e2 >= e3`.
+fn eigenvalues_to_alpha<T: Float>(eigenvalues: &[T]) -> T {
+    let mut sorted = eigenvalues.to_vec();
+    sorted.sort_by(|a, b| b.partial_cmp(a).unwrap_or(Ordering::Equal));
+    let sum = sorted.iter().fold(T::zero(), |sum, v| sum + *v);
+    if sum > T::zero() {
+        (sorted[0] - sorted[1]) / sum
+    } else {
+        T::zero()
+    }
+}
+
+/// Returns the unit tangent and the alpha value,
+/// or `None` if the neighborhood is degenerate,
+/// i.e. its points are (almost) coincident so there is no principal direction.
+fn points_to_tangent_eig<'a, T: Float>(
+    points: impl Iterator<Item = &'a [T; 3]>,
+) -> Option<(Unit<Vector3<T>>, T)> {
     let points_vec: Vec<_> = points.collect();
     let inertia = calc_inertia(points_vec.iter().cloned());
     let eig = inertia.symmetric_eigen();
+    let magnitude = points_vec
+        .iter()
+        .flat_map(|p| p.iter())
+        .fold(T::one(), |max, v| max.max(ComplexField::abs(*v)));
+    let min_spread = T::degenerate_tolerance() * magnitude;
+    let max_eigenvalue = eig.eigenvalues.max();
+    if max_eigenvalue.is_nan()
+        || max_eigenvalue <= min_spread * min_spread * T::from_precision(points_vec.len() as f64)
+    {
+        return None;
+    }
+    let alpha = eigenvalues_to_alpha(eig.eigenvalues.as_slice());
     // TODO: new_unchecked
     // TODO: better copying in general
-    Some(Unit::new_normalize(Vector3::from_iterator(
+    let tangent = Unit::new_normalize(Vector3::from_iterator(
         eig.eigenvectors
             .column(eig.eigenvalues.argmax().0)
             .iter()
             .cloned(),
-    )))
+    ));
+    if tangent.iter().all(|v| v.is_finite()) {
+        Some((tangent, alpha))
+    } else {
+        None
+    }
 }
 
 // ! doesn't work
@@ -247,7 +541,43 @@ fn points_to_tangent_eig<'a>(
 //     })
 // }
 
-fn points_to_rtree(points: &[[Precision; 3]]) -> Result<RTree<PointWithIndex>, &'static str> {
+/// Check that all point coordinates are finite.
+fn check_points<T: Float>(points: &[[T; 3]]) -> Result<(), Error> {
+    match points
+        .iter()
+        .position(|p| p.iter().any(|v| !v.is_finite()))
+    {
+        Some(idx) => Err(Error::NonFinite { idx }),
+        None => Ok(()),
+    }
+}
+
+/// Check that there is a finite tangent and alpha value for every point.
+fn check_tangents_alphas<T: Float>(
+    n_points: usize,
+    tangents: &[Unit<Vector3<T>>],
+    alphas: &[T],
+) -> Result<(), Error> {
+    for other in [tangents.len(), alphas.len()].iter() {
+        if *other != n_points {
+            return Err(Error::MismatchedLengths {
+                points: n_points,
+                other: *other,
+            });
+        }
+    }
+    match tangents
+        .iter()
+        .zip(alphas.iter())
+        .position(|(t, a)| !a.is_finite() || t.iter().any(|v| !v.is_finite()))
+    {
+        Some(idx) => Err(Error::NonFinite { idx }),
+        None => Ok(()),
+    }
+}
+
+fn points_to_rtree<T: Float>(points: &[[T; 3]]) -> Result<RTree<PointWithIndex<T>>, Error> {
+    check_points(points)?;
     Ok(RTree::bulk_load(
         points
             .iter()
@@ -257,163 +587,815 @@ fn points_to_rtree(points: &[[Precision; 3]]) -> Result<RTree<PointWithIndex>, &
     ))
 }
 
-fn points_to_rtree_tangents(
-    points: &[[Precision; 3]], k: usize,
-) -> Result<(RTree<PointWithIndex>, Vec<Unit<Vector3<Precision>>>), &'static str> {
+/// Which points around each point are used to calculate its tangent and alpha value.
+/// Neighborhoods include the point itself.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Neighborhood<T = Precision> {
+    /// The `k` nearest points.
+    Nearest(usize),
+    /// All points within `radius`,
+    /// or the `min_count` nearest points if there are fewer than that within the radius.
+    ///
+    /// Unlike a fixed number of neighbors, this covers a similar length of neuron
+    /// regardless of how densely the point cloud is sampled.
+    Radius { radius: T, min_count: usize },
+}
+
+impl<T: Float> Neighborhood<T> {
+    /// The fewest points any neighborhood can contain.
+    fn min_count(&self) -> usize {
+        match self {
+            Neighborhood::Nearest(k) => *k,
+            Neighborhood::Radius { min_count, .. } => *min_count,
+        }
+    }
+
+    fn neighbors<'a>(
+        &self,
+        rtree: &'a RTree<PointWithIndex<T>>,
+        point: &[T; 3],
+    ) -> Vec<&'a [T; 3]> {
+        let nearest = |k| nearest_points(rtree, point, k);
+        match self {
+            Neighborhood::Nearest(k) => nearest(*k),
+            Neighborhood::Radius { radius, min_count } => {
+                let within: Vec<_> = rtree
+                    .locate_within_distance(*point, *radius * *radius)
+                    .map(|pwd| pwd.position())
+                    .collect();
+                if within.len() < *min_count {
+                    nearest(*min_count)
+                } else {
+                    within
+                }
+            }
+        }
+    }
+}
+
+fn nearest_points<'a, T: Float>(
+    rtree: &'a RTree<PointWithIndex<T>>,
+    point: &[T; 3],
+    k: usize,
+) -> Vec<&'a [T; 3]> {
+    rtree
+        .nearest_neighbor_iter(point)
+        .take(k)
+        .map(|pwd| pwd.position())
+        .collect()
+}
+
+/// What to do with points whose tangent [Neighborhood](enum.Neighborhood.html)
+/// is degenerate, e.g. because it only contains duplicates of the same point.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DegeneratePolicy {
+    /// Fail with [Error::DegenerateNeighborhood](enum.Error.html#variant.DegenerateNeighborhood).
+    Error,
+    /// Leave the point out of the neuron.
+    Skip,
+    /// Use increasingly many nearest points, doubling each time, up to `max_count`.
+    /// Fails if the neighborhood is still degenerate.
+    Widen { max_count: usize },
+    /// Copy the tangent and alpha value of the nearest point whose neighborhood is not degenerate.
+    Inherit,
+}
+
+/// Points (with any skipped points removed), their spatial index, tangents and alpha values,
+/// and the indices of the input points whose neighborhoods were degenerate.
+struct CalculatedTangents<T: Float> {
+    points: Vec<[T; 3]>,
+    rtree: RTree<PointWithIndex<T>>,
+    tangents: Vec<Unit<Vector3<T>>>,
+    alphas: Vec<T>,
+    degenerate: Vec<usize>,
+}
+
+fn points_to_rtree_tangents<T: Float>(
+    points: Vec<[T; 3]>,
+    neighborhood: &Neighborhood<T>,
+    policy: DegeneratePolicy,
+) -> Result<CalculatedTangents<T>, Error> {
+    if let Neighborhood::Radius { radius, .. } = neighborhood {
+        if *radius <= T::zero() || !radius.is_finite() {
+            return Err(Error::InvalidParameter(
+                "Neighborhood radius must be positive and finite",
+            ));
+        }
+    }
+    let k = neighborhood.min_count();
     if points.len() < k {
-        return Err("Too few points to generate tangents");
+        return Err(Error::TooFewPoints {
+            required: k,
+            actual: points.len(),
+        });
+    }
+    let rtree = points_to_rtree(&points)?;
+
+    let mut results: Vec<_> = points
+        .iter()
+        .map(|point| points_to_tangent_eig(neighborhood.neighbors(&rtree, point).into_iter()))
+        .collect();
+    let degenerate: Vec<_> = results
+        .iter()
+        .enumerate()
+        .filter(|(_, r)| r.is_none())
+        .map(|(idx, _)| idx)
+        .collect();
+
+    // only inherit from points which were not degenerate themselves,
+    // whatever order the degenerate points are replaced in
+    let calculated = results.clone();
+    let mut keep = vec![true; points.len()];
+    for idx in degenerate.iter().cloned() {
+        let point = &points[idx];
+        let replacement = match policy {
+            DegeneratePolicy::Error => None,
+            DegeneratePolicy::Skip => {
+                keep[idx] = false;
+                continue;
+            }
+            DegeneratePolicy::Widen { max_count } => {
+                let max_count = max_count.min(points.len());
+                let mut count = k.max(2);
+                let mut found = None;
+                while found.is_none() && count < max_count {
+                    count = (count * 2).min(max_count);
+                    found = points_to_tangent_eig(nearest_points(&rtree, point, count).into_iter());
+                }
+                found
+            }
+            DegeneratePolicy::Inherit => rtree
+                .nearest_neighbor_iter(point)
+                .filter_map(|pwd| calculated[pwd.data])
+                .next(),
+        };
+        match replacement {
+            Some(r) => results[idx] = Some(r),
+            None => return Err(Error::DegenerateNeighborhood { idx }),
+        }
+    }
+
+    let mut out = CalculatedTangents {
+        points: Vec::with_capacity(points.len()),
+        rtree,
+        tangents: Vec::with_capacity(points.len()),
+        alphas: Vec::with_capacity(points.len()),
+        degenerate,
+    };
+    for ((point, result), kept) in points.into_iter().zip(results).zip(keep) {
+        if let (Some((t, a)), true) = (result, kept) {
+            out.points.push(point);
+            out.tangents.push(t);
+            out.alphas.push(a);
+        }
     }
-    let rtree = points_to_rtree(points)?;
+    if out.points.len() < out.rtree.size() {
+        out.rtree = points_to_rtree(&out.points)?;
+    }
+    Ok(out)
+}
 
-    let mut tangents: Vec<Unit<Vector3<Precision>>> = Vec::with_capacity(rtree.size());
+/// Calculate each point's tangent from the skeleton edge to its parent,
+/// or for root points, to their first child.
+/// Edges of zero length, e.g. from a leaf at the same position as its parent,
+/// are skipped in favour of the edge to the nearest ancestor at a different position,
+/// or failing that, the nearest such descendant.
+/// Points with no such edge, e.g. isolated roots, have no tangent.
+fn parents_to_tangents<T: Float>(
+    points: &[[T; 3]],
+    parents: &[Option<usize>],
+) -> Result<Vec<Option<Unit<Vector3<T>>>>, Error> {
+    if parents.len() != points.len() {
+        return Err(Error::MismatchedLengths {
+            points: points.len(),
+            other: parents.len(),
+        });
+    }
+    check_points(points)?;
+    let edge = |from: usize, to: usize| {
+        let diff = Vector3::from(points[to]) - Vector3::from(points[from]);
+        Unit::try_new(diff, T::zero())
+    };
 
-    for point in points.iter() {
-        match points_to_tangent_eig(
-            rtree
-                .nearest_neighbor_iter(&point)
-                .take(k)
-                .map(|pwd| pwd.position()),
-        ) {
-            Some(t) => tangents.push(t),
-            None => return Err("Failed to SVD"),
+    let mut children = vec![Vec::default(); points.len()];
+    for (idx, parent) in parents.iter().enumerate() {
+        if let Some(p_idx) = parent {
+            if *p_idx >= points.len() {
+                return Err(Error::InvalidParameter("Parent index out of bounds"));
+            }
+            children[*p_idx].push(idx);
         }
     }
 
-    Ok((rtree, tangents))
+    // walks are limited to the number of points in case the parents form a cycle
+    let ancestor_edge = |idx: usize| {
+        let mut ancestor = parents[idx];
+        for _ in 0..points.len() {
+            let a_idx = ancestor?;
+            if let Some(e) = edge(a_idx, idx) {
+                return Some(e);
+            }
+            ancestor = parents[a_idx];
+        }
+        None
+    };
+    let descendant_edge = |idx: usize| {
+        let mut descendants: VecDeque<usize> = children[idx].iter().cloned().collect();
+        for _ in 0..points.len() {
+            let d_idx = descendants.pop_front()?;
+            if let Some(e) = edge(idx, d_idx) {
+                return Some(e);
+            }
+            descendants.extend(children[d_idx].iter().cloned());
+        }
+        None
+    };
+
+    Ok((0..points.len())
+        .map(|idx| ancestor_edge(idx).or_else(|| descendant_edge(idx)))
+        .collect())
+}
+
+/// Points (with any skipped points removed), their tangents,
+/// and the indices of the input points which had no skeleton edge of non-zero length.
+type SkeletonTangents<T> = (Vec<[T; 3]>, Vec<Unit<Vector3<T>>>, Vec<usize>);
+
+/// Tangents from the edges of a skeleton, handling points with no edge of non-zero length
+/// according to `policy`.
+///
+/// The whole connected skeleton is already searched for an edge,
+/// so [DegeneratePolicy::Widen](enum.DegeneratePolicy.html#variant.Widen) behaves like
+/// [DegeneratePolicy::Error](enum.DegeneratePolicy.html#variant.Error).
+/// [DegeneratePolicy::Inherit](enum.DegeneratePolicy.html#variant.Inherit) copies the tangent
+/// of the spatially nearest point which has one.
+fn parents_to_tangents_policy<T: Float>(
+    points: Vec<[T; 3]>,
+    parents: &[Option<usize>],
+    policy: DegeneratePolicy,
+) -> Result<SkeletonTangents<T>, Error> {
+    let calculated = parents_to_tangents(&points, parents)?;
+    let degenerate: Vec<_> = calculated
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.is_none())
+        .map(|(idx, _)| idx)
+        .collect();
+    let rtree = match (policy, degenerate.is_empty()) {
+        (DegeneratePolicy::Inherit, false) => Some(points_to_rtree(&points)?),
+        _ => None,
+    };
+
+    let mut out_points = Vec::with_capacity(points.len());
+    let mut tangents = Vec::with_capacity(points.len());
+    for (idx, (point, tangent)) in points.into_iter().zip(calculated.iter()).enumerate() {
+        let tangent = match (tangent, policy, &rtree) {
+            (Some(t), _, _) => *t,
+            (None, DegeneratePolicy::Skip, _) => continue,
+            (None, DegeneratePolicy::Inherit, Some(rtree)) => rtree
+                .nearest_neighbor_iter(&point)
+                .filter_map(|pwd| calculated[pwd.data])
+                .next()
+                .ok_or(Error::DegenerateNeighborhood { idx })?,
+            (None, _, _) => return Err(Error::DegenerateNeighborhood { idx }),
+        };
+        out_points.push(point);
+        tangents.push(tangent);
+    }
+    Ok((out_points, tangents, degenerate))
 }
 
-impl QueryPointTangents {
+impl<T: Float> QueryPointTangents<T> {
     /// Calculates tangents from the given points.
     /// Note that this constructs a spatial index in order to calculate the tangents,
     /// and then throws it away: you may as well use a [TargetNeuron](trait.TargetNeuron.html)
     /// type, with regards to performance.
     /// `k` is the number of points tangents will be calculated with,
     /// and includes the point itself.
-    pub fn new(points: Vec<[Precision; 3]>, k: usize) -> Result<Self, &'static str> {
-        points_to_rtree_tangents(&points, k).map(|(_, tangents)| Self { points, tangents })
+    pub fn new(points: Vec<[T; 3]>, k: usize) -> Result<Self, Error> {
+        Self::new_with_neighborhood(points, &Neighborhood::Nearest(k))
+    }
+
+    /// As [new](#method.new), with more control over which points each tangent is calculated with.
+    pub fn new_with_neighborhood(
+        points: Vec<[T; 3]>,
+        neighborhood: &Neighborhood<T>,
+    ) -> Result<Self, Error> {
+        Self::new_with_policy(points, neighborhood, DegeneratePolicy::Error).map(|(n, _)| n)
+    }
+
+    /// As [new_with_neighborhood](#method.new_with_neighborhood),
+    /// handling degenerate neighborhoods according to `policy`.
+    /// Also returns the indices of the input points whose neighborhoods were degenerate.
+    pub fn new_with_policy(
+        points: Vec<[T; 3]>,
+        neighborhood: &Neighborhood<T>,
+        policy: DegeneratePolicy,
+    ) -> Result<(Self, Vec<usize>), Error> {
+        points_to_rtree_tangents(points, neighborhood, policy).map(|calc| {
+            (
+                Self {
+                    points: calc.points,
+                    tangents: calc.tangents,
+                    alphas: calc.alphas,
+                },
+                calc.degenerate,
+            )
+        })
+    }
+
+    /// Calculate tangents from the edges of a skeleton, rather than from nearest neighbors.
+    /// See [IndexedPointTangents::new_with_parents](struct.IndexedPointTangents.html#method.new_with_parents).
+    pub fn new_with_parents(
+        points: Vec<[T; 3]>,
+        parents: &[Option<usize>],
+    ) -> Result<Self, Error> {
+        Self::new_with_parents_policy(points, parents, DegeneratePolicy::Error).map(|(n, _)| n)
+    }
+
+    /// As [new_with_parents](#method.new_with_parents),
+    /// handling points with no skeleton edge of non-zero length according to `policy`.
+    /// See [IndexedPointTangents::new_with_parents_policy](struct.IndexedPointTangents.html#method.new_with_parents_policy).
+    pub fn new_with_parents_policy(
+        points: Vec<[T; 3]>,
+        parents: &[Option<usize>],
+        policy: DegeneratePolicy,
+    ) -> Result<(Self, Vec<usize>), Error> {
+        let (points, tangents, degenerate) = parents_to_tangents_policy(points, parents, policy)?;
+        let alphas = vec![T::one(); tangents.len()];
+        Self::new_with_tangents_alphas(points, tangents, alphas).map(|n| (n, degenerate))
+    }
+
+    /// Use pre-calculated tangents and alpha values.
+    pub fn new_with_tangents_alphas(
+        points: Vec<[T; 3]>,
+        tangents: Vec<Unit<Vector3<T>>>,
+        alphas: Vec<T>,
+    ) -> Result<Self, Error> {
+        check_points(&points)?;
+        check_tangents_alphas(points.len(), &tangents, &alphas)?;
+        Ok(Self {
+            points,
+            tangents,
+            alphas,
+        })
     }
 }
 
-impl QueryNeuron for QueryPointTangents {
+impl<T: Float> QueryNeuron<T> for QueryPointTangents<T> {
     fn len(&self) -> usize {
         self.points.len()
     }
 
     fn query(
         &self,
-        target: &impl TargetNeuron,
-        score_fn: &impl Fn(&DistDot) -> Precision,
-    ) -> Precision {
-        let mut score_total: Precision = 0.0;
-        for (q_pt, q_tan) in self.points.iter().zip(self.tangents.iter()) {
-            score_total += score_fn(&target.nearest_match_dist_dot(q_pt, q_tan));
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> T {
+        let mut score_total = T::zero();
+        for ((q_pt, q_tan), q_alpha) in self
+            .points
+            .iter()
+            .zip(self.tangents.iter())
+            .zip(self.alphas.iter())
+        {
+            let alpha = if use_alpha { Some(*q_alpha) } else { None };
+            score_total += score_fn(&target.nearest_match_dist_dot(q_pt, q_tan, alpha, None));
         }
         score_total
     }
 
-    fn points(&self) -> Vec<[Precision; 3]> {
+    fn query_bounded(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+        threshold: T,
+        max_point_score: T,
+    ) -> BoundedScore<T> {
+        let scores = self
+            .points
+            .iter()
+            .zip(self.tangents.iter())
+            .zip(self.alphas.iter())
+            .map(|((q_pt, q_tan), q_alpha)| {
+                let alpha = if use_alpha { Some(*q_alpha) } else { None };
+                score_fn(&target.nearest_match_dist_dot(q_pt, q_tan, alpha, None))
+            });
+        sum_bounded(scores, self.len(), threshold, max_point_score)
+    }
+
+    fn query_matches(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> Vec<PointMatch<T>> {
+        self.points
+            .iter()
+            .zip(self.tangents.iter())
+            .zip(self.alphas.iter())
+            .enumerate()
+            .map(|(query_idx, ((q_pt, q_tan), q_alpha))| {
+                let alpha = if use_alpha { Some(*q_alpha) } else { None };
+                let (target_idx, dist_dot) = target.nearest_match(q_pt, q_tan, alpha);
+                PointMatch {
+                    query_idx,
+                    target_idx,
+                    dist_dot,
+                    score: score_fn(&dist_dot),
+                }
+            })
+            .collect()
+    }
+
+    fn points(&self) -> Vec<[T; 3]> {
         self.points.clone()
     }
 
-    fn tangents(&self) -> Vec<Unit<Vector3<Precision>>> {
+    fn tangents(&self) -> Vec<Unit<Vector3<T>>> {
         self.tangents.clone()
     }
+
+    fn alphas(&self) -> Vec<T> {
+        self.alphas.clone()
+    }
 }
 
-pub trait TargetNeuron: QueryNeuron {
+pub trait TargetNeuron<T: Float = Precision>: QueryNeuron<T> {
     /// For a given point and tangent vector,
     /// get the distance to its nearest point in the target, and the absolute dot product
     /// with that neighbor's tangent (i.e. absolute cosine of the angle, as they are both unit-length).
+    /// If `alpha` is given, the dot product is scaled by the geometric mean of
+    /// that value and the neighbor's alpha.
+    ///
+    /// If `max_dist` is given and there is no point within that distance,
+    /// return [DistDot::out_of_range](struct.DistDot.html#method.out_of_range) instead.
+    /// Implementations can use this to avoid a full nearest-neighbor search,
+    /// e.g. beyond the outermost distance bin of a score table,
+    /// where the exact distance does not affect the score.
     fn nearest_match_dist_dot(
         &self,
-        point: &[Precision; 3],
-        tangent: &Unit<Vector3<Precision>>,
-    ) -> DistDot;
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+        max_dist: Option<T>,
+    ) -> DistDot<T> {
+        let dd = self.nearest_match(point, tangent, alpha).1;
+        match max_dist {
+            Some(m) if dd.dist > m => DistDot::out_of_range(),
+            _ => dd,
+        }
+    }
+
+    /// As [nearest_match_dist_dot](#method.nearest_match_dist_dot),
+    /// but also return the index of the nearest point,
+    /// consistent with [points](trait.QueryNeuron.html#tymethod.points).
+    fn nearest_match(
+        &self,
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+    ) -> (usize, DistDot<T>);
 }
 
-/// Target neuron using an [R*-tree](https://en.wikipedia.org/wiki/R*_tree) for spatial queries.
+/// Target neuron which uses the [SpatialIndex](spatial/trait.SpatialIndex.html) `I`
+/// to find the nearest point to each query point.
+/// All indices share the same constructors;
+/// see [RStarPointTangents](type.RStarPointTangents.html),
+/// [KdTreePointTangents](type.KdTreePointTangents.html),
+/// [GridPointTangents](type.GridPointTangents.html),
+/// [BruteForcePointTangents](type.BruteForcePointTangents.html)
+/// and [DistanceFieldPointTangents](type.DistanceFieldPointTangents.html).
+///
+/// Searches within a maximum distance first check the distance to the neuron's bounding box.
+/// The index owns the points, so as a query, they are iterated in the index's order.
 #[derive(Clone)]
-pub struct RStarPointTangents {
-    rtree: RTree<PointWithIndex>,
-    tangents: Vec<Unit<Vector3<Precision>>>,
+pub struct IndexedPointTangents<T: Float, I: SpatialIndex<T>> {
+    index: I,
+    tangents: Vec<Unit<Vector3<T>>>,
+    alphas: Vec<T>,
+    /// Lower and upper corners of the bounding box.
+    bounds: [[T; 3]; 2],
 }
 
-impl RStarPointTangents {
-    /// Calculate tangents from constructed R*-tree.
+/// Target neuron using an [R*-tree](https://en.wikipedia.org/wiki/R*_tree) for spatial queries.
+pub type RStarPointTangents<T = Precision> = IndexedPointTangents<T, RStarIndex<T>>;
+
+/// Target neuron using a static [k-d tree](https://en.wikipedia.org/wiki/K-d_tree) for spatial queries.
+pub type KdTreePointTangents<T = Precision> = IndexedPointTangents<T, KdTree<T>>;
+
+/// Target neuron using a uniform grid for spatial queries.
+pub type GridPointTangents<T = Precision> = IndexedPointTangents<T, GridHash<T>>;
+
+/// Target neuron which compares every point for spatial queries;
+/// only suitable for small neurons.
+pub type BruteForcePointTangents<T = Precision> = IndexedPointTangents<T, BruteForce<T>>;
+
+/// Target neuron using a precomputed [DistanceField](spatial/struct.DistanceField.html)
+/// for spatial queries, for targets which are queried many times.
+/// The constructors use a default memory budget:
+/// see [from_target](#method.from_target) to choose it.
+pub type DistanceFieldPointTangents<T = Precision> = IndexedPointTangents<T, DistanceField<T>>;
+
+impl<T: Float, I: SpatialIndex<T>> IndexedPointTangents<T, I> {
+    /// Calculate tangents and alpha values from the given points.
     /// `k` is the number of points to calculate each tangent with.
-    pub fn new(points: Vec<[Precision; 3]>, k: usize) -> Result<Self, &'static str> {
-        points_to_rtree_tangents(&points, k).map(|(rtree, tangents)| Self { rtree, tangents })
+    pub fn new(points: Vec<[T; 3]>, k: usize) -> Result<Self, Error> {
+        Self::new_with_neighborhood(points, &Neighborhood::Nearest(k))
+    }
+
+    /// As [new](#method.new), with more control over which points each tangent is calculated with.
+    pub fn new_with_neighborhood(
+        points: Vec<[T; 3]>,
+        neighborhood: &Neighborhood<T>,
+    ) -> Result<Self, Error> {
+        Self::new_with_policy(points, neighborhood, DegeneratePolicy::Error).map(|(n, _)| n)
+    }
+
+    /// As [new_with_neighborhood](#method.new_with_neighborhood),
+    /// handling degenerate neighborhoods according to `policy`.
+    /// Also returns the indices of the input points whose neighborhoods were degenerate:
+    /// with [DegeneratePolicy::Skip](enum.DegeneratePolicy.html#variant.Skip),
+    /// these points are not in the neuron and later points' indices are shifted.
+    pub fn new_with_policy(
+        points: Vec<[T; 3]>,
+        neighborhood: &Neighborhood<T>,
+        policy: DegeneratePolicy,
+    ) -> Result<(Self, Vec<usize>), Error> {
+        if points.is_empty() {
+            return Err(Error::EmptyTarget);
+        }
+        let calc = points_to_rtree_tangents(points, neighborhood, policy)?;
+        if calc.points.is_empty() {
+            return Err(Error::EmptyTarget);
+        }
+        let rtree = calc.rtree;
+        let neuron = QueryPointTangents {
+            points: calc.points,
+            tangents: calc.tangents,
+            alphas: calc.alphas,
+        };
+        let target = Self::from_parts(neuron, |points| I::from_rtree(rtree, points));
+        Ok((target, calc.degenerate))
+    }
+
+    /// Calculate tangents from the edges of a skeleton, rather than from nearest neighbors,
+    /// so that branches which pass close to each other do not affect each other's tangents.
+    /// `parents` holds the index of each point's parent, or `None` for roots,
+    /// e.g. from [Swc::resample_with_parents](io/swc/struct.Swc.html#method.resample_with_parents).
+    ///
+    /// Each tangent is the direction of the edge to the point's parent,
+    /// or to the first child for roots.
+    /// Where that edge has zero length, e.g. for a leaf at the same position as its parent,
+    /// the edge to the nearest ancestor at a different position is used,
+    /// or failing that, the nearest such descendant.
+    /// All alpha values are set to 1, so alpha weighting has no effect.
+    ///
+    /// Fails with [Error::DegenerateNeighborhood](enum.Error.html#variant.DegenerateNeighborhood)
+    /// if a point has no such edge, e.g. an isolated root:
+    /// see [new_with_parents_policy](#method.new_with_parents_policy) to handle these instead.
+    pub fn new_with_parents(
+        points: Vec<[T; 3]>,
+        parents: &[Option<usize>],
+    ) -> Result<Self, Error> {
+        Self::new_with_parents_policy(points, parents, DegeneratePolicy::Error).map(|(n, _)| n)
+    }
+
+    /// As [new_with_parents](#method.new_with_parents),
+    /// handling points with no skeleton edge of non-zero length according to `policy`.
+    /// Also returns the indices of the input points which had no such edge.
+    ///
+    /// The whole connected skeleton is already searched for an edge,
+    /// so [DegeneratePolicy::Widen](enum.DegeneratePolicy.html#variant.Widen) fails
+    /// like [DegeneratePolicy::Error](enum.DegeneratePolicy.html#variant.Error).
+    /// [DegeneratePolicy::Inherit](enum.DegeneratePolicy.html#variant.Inherit) copies the tangent
+    /// of the nearest point in space which has one.
+    pub fn new_with_parents_policy(
+        points: Vec<[T; 3]>,
+        parents: &[Option<usize>],
+        policy: DegeneratePolicy,
+    ) -> Result<(Self, Vec<usize>), Error> {
+        let (points, tangents, degenerate) = parents_to_tangents_policy(points, parents, policy)?;
+        Self::new_with_tangents(points, tangents).map(|n| (n, degenerate))
     }
 
     /// Use pre-calculated tangents.
+    /// All alpha values are set to 1, so alpha weighting has no effect.
     pub fn new_with_tangents(
-        points: Vec<[Precision; 3]>,
-        tangents: Vec<Unit<Vector3<Precision>>>,
-    ) -> Result<Self, &'static str> {
-        points_to_rtree(&points).map(|rtree| Self { rtree, tangents })
+        points: Vec<[T; 3]>,
+        tangents: Vec<Unit<Vector3<T>>>,
+    ) -> Result<Self, Error> {
+        let alphas = vec![T::one(); tangents.len()];
+        Self::new_with_tangents_alphas(points, tangents, alphas)
+    }
+
+    /// Use pre-calculated tangents and alpha values,
+    /// e.g. from NAT's dotprops.
+    pub fn new_with_tangents_alphas(
+        points: Vec<[T; 3]>,
+        tangents: Vec<Unit<Vector3<T>>>,
+        alphas: Vec<T>,
+    ) -> Result<Self, Error> {
+        if points.is_empty() {
+            return Err(Error::EmptyTarget);
+        }
+        let neuron = QueryPointTangents::new_with_tangents_alphas(points, tangents, alphas)?;
+        Ok(Self::from_parts(neuron, I::from_points))
+    }
+
+    /// Hand the neuron's points over to the index built by `build_index`.
+    fn from_parts(neuron: QueryPointTangents<T>, build_index: impl FnOnce(Vec<[T; 3]>) -> I) -> Self {
+        let mut bounds = [neuron.points[0]; 2];
+        for point in neuron.points.iter() {
+            for dim in 0..3 {
+                bounds[0][dim] = bounds[0][dim].min(point[dim]);
+                bounds[1][dim] = bounds[1][dim].max(point[dim]);
+            }
+        }
+        Self {
+            index: build_index(neuron.points),
+            tangents: neuron.tangents,
+            alphas: neuron.alphas,
+            bounds,
+        }
+    }
+
+    /// The query alpha value of the point with the given index, if alpha is used.
+    fn query_alpha(&self, idx: usize, use_alpha: bool) -> Option<T> {
+        if use_alpha {
+            Some(self.alphas[idx])
+        } else {
+            None
+        }
+    }
+
+    /// Squared distance from the point to the neuron's bounding box.
+    fn bounds_dist2(&self, point: &[T; 3]) -> T {
+        (0..3).fold(T::zero(), |sum, dim| {
+            let outside = (self.bounds[0][dim] - point[dim])
+                .max(point[dim] - self.bounds[1][dim])
+                .max(T::zero());
+            sum + outside * outside
+        })
+    }
+
+    fn dist_dot(
+        &self,
+        idx: usize,
+        dist2: T,
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+    ) -> DistDot<T> {
+        let mut dot = ComplexField::abs(self.tangents[idx].dot(tangent));
+        if let Some(a) = alpha {
+            dot *= (a * self.alphas[idx]).sqrt();
+        }
+        DistDot {
+            dist: dist2.sqrt(),
+            dot,
+        }
+    }
+}
+
+impl<T: Float> IndexedPointTangents<T, DistanceField<T>> {
+    /// Build a distance field over the points of a target neuron with any other index,
+    /// keeping its tangents and alpha values.
+    /// The grid uses at most `memory_budget` bytes;
+    /// see [DistanceField::new](spatial/struct.DistanceField.html#method.new).
+    pub fn from_target<I: SpatialIndex<T>>(
+        target: IndexedPointTangents<T, I>,
+        memory_budget: usize,
+    ) -> Result<Self, Error> {
+        let index = DistanceField::new(&target.index.points(), memory_budget)?;
+        Ok(Self {
+            index,
+            tangents: target.tangents,
+            alphas: target.alphas,
+            bounds: target.bounds,
+        })
     }
 }
 
-impl QueryNeuron for RStarPointTangents {
+impl<T: Float, I: SpatialIndex<T>> QueryNeuron<T> for IndexedPointTangents<T, I> {
     fn len(&self) -> usize {
         self.tangents.len()
     }
 
     fn query(
         &self,
-        target: &impl TargetNeuron,
-        score_fn: &impl Fn(&DistDot) -> Precision,
-    ) -> Precision {
-        let mut score_total: Precision = 0.0;
-        for q_pt_idx in self.rtree.iter() {
-            let dd =
-                target.nearest_match_dist_dot(q_pt_idx.position(), &self.tangents[q_pt_idx.data]);
-            let score = score_fn(&dd);
-            score_total += score;
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> T {
+        let mut score_total = T::zero();
+        for (idx, q_pt) in self.index.iter_points() {
+            let alpha = self.query_alpha(idx, use_alpha);
+            score_total += score_fn(&target.nearest_match_dist_dot(
+                q_pt,
+                &self.tangents[idx],
+                alpha,
+                None,
+            ));
         }
         score_total
     }
 
-    fn points(&self) -> Vec<[Precision; 3]> {
-        let mut unsorted: Vec<&PointWithIndex> = self.rtree.iter().collect();
-        unsorted.sort_by_key(|pwd| pwd.data);
-        unsorted.into_iter().map(|pwd| *pwd.position()).collect()
+    fn query_bounded(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+        threshold: T,
+        max_point_score: T,
+    ) -> BoundedScore<T> {
+        let scores = self.index.iter_points().map(|(idx, q_pt)| {
+            let alpha = self.query_alpha(idx, use_alpha);
+            score_fn(&target.nearest_match_dist_dot(q_pt, &self.tangents[idx], alpha, None))
+        });
+        sum_bounded(scores, self.len(), threshold, max_point_score)
+    }
+
+    fn query_matches(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> Vec<PointMatch<T>> {
+        let mut matches: Vec<_> = self
+            .index
+            .iter_points()
+            .map(|(query_idx, q_pt)| {
+                let alpha = self.query_alpha(query_idx, use_alpha);
+                let (target_idx, dist_dot) =
+                    target.nearest_match(q_pt, &self.tangents[query_idx], alpha);
+                PointMatch {
+                    query_idx,
+                    target_idx,
+                    dist_dot,
+                    score: score_fn(&dist_dot),
+                }
+            })
+            .collect();
+        matches.sort_unstable_by_key(|m| m.query_idx);
+        matches
+    }
+
+    fn points(&self) -> Vec<[T; 3]> {
+        self.index.points()
     }
 
-    fn tangents(&self) -> Vec<Unit<Vector3<Precision>>> {
+    fn tangents(&self) -> Vec<Unit<Vector3<T>>> {
         self.tangents.clone()
     }
+
+    fn alphas(&self) -> Vec<T> {
+        self.alphas.clone()
+    }
 }
 
-impl TargetNeuron for RStarPointTangents {
+impl<T: Float, I: SpatialIndex<T>> TargetNeuron<T> for IndexedPointTangents<T, I> {
     fn nearest_match_dist_dot(
         &self,
-        point: &[Precision; 3],
-        tangent: &Unit<Vector3<Precision>>,
-    ) -> DistDot {
-        self.rtree
-            .nearest_neighbor_iter_with_distance(point)
-            .next()
-            .map(|(element, dist2)| {
-                let this_tangent = self.tangents[element.data];
-                let dot = this_tangent.dot(tangent).abs();
-                DistDot {
-                    dist: dist2.sqrt(),
-                    dot,
-                }
-            })
-            .expect("impossible")
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+        max_dist: Option<T>,
+    ) -> DistDot<T> {
+        if let Some(m) = max_dist {
+            if self.bounds_dist2(point) > m * m {
+                return DistDot::out_of_range();
+            }
+        }
+        match self.index.nearest(point, max_dist) {
+            Some((idx, dist2)) => self.dist_dot(idx, dist2, tangent, alpha),
+            None => DistDot::out_of_range(),
+        }
+    }
+
+    fn nearest_match(
+        &self,
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+    ) -> (usize, DistDot<T>) {
+        let (idx, dist2) = self
+            .index
+            .nearest(point, None)
+            .expect("constructors ensure the neuron is not empty");
+        (idx, self.dist_dot(idx, dist2, tangent, alpha))
     }
 }
 
 // ? consider using nalgebra's Point3 in PointWithIndex, for consistency
 // ^ can't implement rstar::Point for nalgebra::geometry::Point3 because of orphan rules
-// TODO: replace Precision with float generic
 
 /// Given the upper bounds of a number of bins, find which bin the value falls into.
-/// Values outside of the range fall into the bottom or top bin.
-fn find_bin_binary(value: Precision, upper_bounds: &[Precision]) -> usize {
-    let raw = match upper_bounds.binary_search_by(|bound| bound.partial_cmp(&value).unwrap()) {
+/// Values outside of the range, and NaN, fall into the bottom or top bin.
+fn find_bin_binary<T: Float>(value: T, upper_bounds: &[T]) -> usize {
+    let raw = match upper_bounds
+        .binary_search_by(|bound| bound.partial_cmp(&value).unwrap_or(Ordering::Less))
+    {
         Ok(v) => v + 1,
         Err(v) => v,
     };
@@ -425,6 +1407,37 @@ fn find_bin_binary(value: Precision, upper_bounds: &[Precision]) -> usize {
     }
 }
 
+/// Midpoint of the bin at the given index;
+/// the lower bound is implicitly the previous bin's upper bound, or zero.
+fn bin_centre<T: Float>(idx: usize, upper_bounds: &[T]) -> T {
+    let lower = if idx == 0 {
+        T::zero()
+    } else {
+        upper_bounds[idx - 1]
+    };
+    (lower + upper_bounds[idx]) / T::from_precision(2.0)
+}
+
+/// Given the upper bounds of a number of bins, find the two adjacent bins
+/// whose centres the value falls between, and the weight of the upper one.
+/// Values outside of the outermost centres are clamped to the bottom or top bin.
+fn find_interp_bins<T: Float>(value: T, upper_bounds: &[T]) -> (usize, usize, T) {
+    let mut lower_centre = bin_centre(0, upper_bounds);
+    if value.is_nan() || value <= lower_centre {
+        return (0, 0, T::zero());
+    }
+    for idx in 1..upper_bounds.len() {
+        let upper_centre = bin_centre(idx, upper_bounds);
+        if value < upper_centre {
+            let weight = (value - lower_centre) / (upper_centre - lower_centre);
+            return (idx - 1, idx, weight);
+        }
+        lower_centre = upper_centre;
+    }
+    let highest = upper_bounds.len() - 1;
+    (highest, highest, T::zero())
+}
+
 // fn find_bin_linear(value: Precision, upper_bounds: &[Precision]) -> usize {
 //     let mut out = 0;
 //     for bound in upper_bounds.iter() {
@@ -448,62 +1461,380 @@ fn find_bin_binary(value: Precision, upper_bounds: &[Precision]) -> usize {
 /// Each bin is identified by its upper bound:
 /// the lower bound is implicitly the previous bin's upper bound, or zero.
 /// The output is constrained to the limits of the table.
-pub fn table_to_fn(
+///
+/// Fails if the table is not valid; see [ScoreMatrix](struct.ScoreMatrix.html).
+pub fn table_to_fn<T: Float>(
     dist_thresholds: Vec<Precision>,
     dot_thresholds: Vec<Precision>,
     cells: Vec<Precision>,
-) -> impl Fn(&DistDot) -> Precision {
-    if dist_thresholds.len() * dot_thresholds.len() != cells.len() {
-        panic!("Number of cells in table do not match number of columns/rows");
-    }
+) -> Result<impl Fn(&DistDot<T>) -> T, Error> {
+    ScoreMatrix::new(dist_thresholds, dot_thresholds, cells).map(ScoreMatrix::to_fn)
+}
 
-    move |dd: &DistDot| -> Precision {
-        let col_idx = find_bin_binary(dd.dot, &dot_thresholds);
-        let row_idx = find_bin_binary(dd.dist, &dist_thresholds);
+/// Find the linear index of the cell of a dist-major table which the point match falls into.
+/// NaN distances and dot products fall into the furthest distance and lowest dot product bins.
+fn table_idx<T: Float>(dist_thresholds: &[T], dot_thresholds: &[T], dd: &DistDot<T>) -> usize {
+    let dd = dd.nan_to_worst();
+    let col_idx = find_bin_binary(dd.dot, dot_thresholds);
+    let row_idx = find_bin_binary(dd.dist, dist_thresholds);
 
-        let lin_idx = row_idx * dot_thresholds.len() + col_idx;
-        cells[lin_idx]
-    }
+    row_idx * dot_thresholds.len() + col_idx
 }
 
-/// Struct for caching a number of neurons for multiple comparable NBLAST queries.
-#[derive(Clone)]
-pub struct NblastArena<N, F>
-where
-    N: TargetNeuron,
-    F: Fn(&DistDot) -> Precision,
-{
-    neurons_scores: Vec<(N, Precision)>,
-    score_fn: F,
+/// Find the cell of a dist-major table which the point match falls into.
+fn table_lookup<T: Float>(
+    dist_thresholds: &[T],
+    dot_thresholds: &[T],
+    cells: &[T],
+    dd: &DistDot<T>,
+) -> T {
+    cells[table_idx(dist_thresholds, dot_thresholds, dd)]
 }
 
-pub type NeuronIdx = usize;
+/// Convert an empirically-derived table into a function which can be passed to neuron queries,
+/// like [table_to_fn](fn.table_to_fn.html),
+/// but bilinearly interpolating between the centres of adjacent bins
+/// rather than taking the value of the bin the point match falls into.
+/// This makes the score continuous across bin boundaries.
+///
+/// Values beyond the centres of the outermost bins take the value at that edge of the table.
+pub fn table_to_interp_fn<T: Float>(
+    dist_thresholds: Vec<Precision>,
+    dot_thresholds: Vec<Precision>,
+    cells: Vec<Precision>,
+) -> Result<impl Fn(&DistDot<T>) -> T, Error> {
+    ScoreMatrix::new(dist_thresholds, dot_thresholds, cells).map(ScoreMatrix::to_interp_fn)
+}
 
-// TODO: caching strategy
-impl<N, F> NblastArena<N, F>
-where
-    N: TargetNeuron,
-    F: Fn(&DistDot) -> Precision,
-{
-    pub fn new(score_fn: F) -> Self {
-        Self {
-            neurons_scores: Vec::default(),
-            score_fn,
-        }
-    }
+/// Bilinearly interpolate the value of a dist-major table at the point match.
+fn table_interp_lookup<T: Float>(
+    dist_thresholds: &[T],
+    dot_thresholds: &[T],
+    cells: &[T],
+    dd: &DistDot<T>,
+) -> T {
+    let dd = dd.nan_to_worst();
+    let (row_lo, row_hi, row_weight) = find_interp_bins(dd.dist, dist_thresholds);
+    let (col_lo, col_hi, col_weight) = find_interp_bins(dd.dot, dot_thresholds);
+    let cell = |row_idx: usize, col_idx: usize| cells[row_idx * dot_thresholds.len() + col_idx];
+
+    let lo = cell(row_lo, col_lo) * (T::one() - col_weight) + cell(row_lo, col_hi) * col_weight;
+    let hi = cell(row_hi, col_lo) * (T::one() - col_weight) + cell(row_hi, col_hi) * col_weight;
+    lo * (T::one() - row_weight) + hi * row_weight
+}
+
+/// Target neuron which passes a maximum search distance to every nearest-neighbor search
+/// made by a query, so that queries need not know about it.
+struct MaxDistTarget<'a, N, T> {
+    target: &'a N,
+    max_dist: Option<T>,
+}
+
+impl<'a, N: TargetNeuron<T>, T: Float> QueryNeuron<T> for MaxDistTarget<'a, N, T> {
+    fn len(&self) -> usize {
+        self.target.len()
+    }
+
+    fn query(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> T {
+        self.target.query(target, use_alpha, score_fn)
+    }
+
+    fn query_bounded(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+        threshold: T,
+        max_point_score: T,
+    ) -> BoundedScore<T> {
+        self.target
+            .query_bounded(target, use_alpha, score_fn, threshold, max_point_score)
+    }
+
+    fn query_matches(
+        &self,
+        target: &impl TargetNeuron<T>,
+        use_alpha: bool,
+        score_fn: &impl Fn(&DistDot<T>) -> T,
+    ) -> Vec<PointMatch<T>> {
+        self.target.query_matches(target, use_alpha, score_fn)
+    }
+
+    fn points(&self) -> Vec<[T; 3]> {
+        self.target.points()
+    }
+
+    fn tangents(&self) -> Vec<Unit<Vector3<T>>> {
+        self.target.tangents()
+    }
+
+    fn alphas(&self) -> Vec<T> {
+        self.target.alphas()
+    }
+}
+
+impl<'a, N: TargetNeuron<T>, T: Float> TargetNeuron<T> for MaxDistTarget<'a, N, T> {
+    fn nearest_match_dist_dot(
+        &self,
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+        max_dist: Option<T>,
+    ) -> DistDot<T> {
+        let max_dist = match (self.max_dist, max_dist) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        self.target
+            .nearest_match_dist_dot(point, tangent, alpha, max_dist)
+    }
+
+    fn nearest_match(
+        &self,
+        point: &[T; 3],
+        tangent: &Unit<Vector3<T>>,
+        alpha: Option<T>,
+    ) -> (usize, DistDot<T>) {
+        self.target.nearest_match(point, tangent, alpha)
+    }
+}
+
+/// Boxed score function, so that arenas using different score functions have the same type.
+pub type BoxedScoreFn<T = Precision> = Box<dyn Fn(&DistDot<T>) -> T + Send + Sync>;
+
+/// Struct for caching a number of neurons for multiple comparable NBLAST queries.
+///
+/// Each neuron is identified by the [NeuronIdx](type.NeuronIdx.html) returned when it is added,
+/// which stays valid however many other neurons are added or removed:
+/// identifiers are never reused.
+#[derive(Clone)]
+pub struct NblastArena<N, F, T = Precision>
+where
+    N: TargetNeuron<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    /// Removed neurons leave a gap, so that identifiers are stable.
+    neurons_scores: Vec<Option<(N, T)>>,
+    /// Number of neurons present, i.e. not counting the gaps.
+    n_neurons: usize,
+    /// Name and metadata of every neuron present.
+    info: HashMap<NeuronIdx, NeuronInfo>,
+    names: HashMap<String, NeuronIdx>,
+    score_fn: F,
+    /// Description of the score function, if the arena was built from one,
+    /// which is written to arena files.
+    score_fn_record: Option<ScoreFnRecord>,
+    use_alpha: bool,
+    max_dist: Option<T>,
+    /// Thread pool for the parallel query methods, if not rayon's global pool.
+    #[cfg(feature = "parallel")]
+    thread_pool: Option<Arc<rayon::ThreadPool>>,
+}
+
+/// Stable identifier of a neuron in an [NblastArena](struct.NblastArena.html).
+pub type NeuronIdx = usize;
+
+/// Optional unique name and key/value metadata of a neuron in an arena.
+#[derive(Debug, Clone, Default, PartialEq)]
+struct NeuronInfo {
+    name: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+/// Target index and score, ordered so that better matches
+/// (higher score, then lower index) are greater.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct ScoredTarget<T> {
+    score: T,
+    idx: NeuronIdx,
+}
+
+impl<T: Float> Eq for ScoredTarget<T> {}
+
+impl<T: Float> PartialOrd for ScoredTarget<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T: Float> Ord for ScoredTarget<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score
+            .partial_cmp(&other.score)
+            .unwrap_or(Ordering::Equal)
+            .then_with(|| other.idx.cmp(&self.idx))
+    }
+}
+
+// TODO: caching strategy
+impl<N, F, T> NblastArena<N, F, T>
+where
+    N: TargetNeuron<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    /// `use_alpha` determines whether point matches are weighted by
+    /// their alpha values, for all queries and self-hit scores.
+    pub fn new(score_fn: F, use_alpha: bool) -> Self {
+        Self {
+            neurons_scores: Vec::default(),
+            n_neurons: 0,
+            info: HashMap::default(),
+            names: HashMap::default(),
+            score_fn,
+            score_fn_record: None,
+            use_alpha,
+            max_dist: None,
+            #[cfg(feature = "parallel")]
+            thread_pool: None,
+        }
+    }
+
+    /// Stop searching for each query point's nearest target point beyond `max_dist`,
+    /// scoring it as [DistDot::out_of_range](struct.DistDot.html#method.out_of_range) instead.
+    /// This saves most of the time spent on distant neuron pairs,
+    /// and does not change their scores if the score function is constant beyond `max_dist`:
+    /// out-of-range matches have a dot product of 0.
+    /// Point matches and self-hit scores are not affected.
+    ///
+    /// See [from_score_matrix](#method.from_score_matrix) to set this
+    /// only where it does not change scores.
+    pub fn set_max_dist(&mut self, max_dist: Option<T>) {
+        self.max_dist = max_dist;
+    }
+
+    /// The maximum nearest-neighbor search distance, if any.
+    pub fn max_dist(&self) -> Option<T> {
+        self.max_dist
+    }
+
+    /// Query `target` with `query`, searching within the maximum distance.
+    fn query_neurons(&self, query: &impl QueryNeuron<T>, target: &impl TargetNeuron<T>) -> T {
+        let target = MaxDistTarget {
+            target,
+            max_dist: self.max_dist,
+        };
+        query.query(&target, self.use_alpha, &self.score_fn)
+    }
 
     fn next_id(&self) -> NeuronIdx {
         self.neurons_scores.len()
     }
 
+    /// The neuron with the given index and its self-hit score, if it is present.
+    fn neuron_score(&self, idx: NeuronIdx) -> Option<&(N, T)> {
+        self.neurons_scores.get(idx).and_then(Option::as_ref)
+    }
+
     /// Returns an index which is then used to make queries.
+    /// The index is never reused, even if the neuron is removed.
     pub fn add_neuron(&mut self, neuron: N) -> NeuronIdx {
         let idx = self.next_id();
-        let score = neuron.self_hit(&self.score_fn);
-        self.neurons_scores.push((neuron, score));
+        let score = neuron.self_hit(self.use_alpha, &self.score_fn);
+        self.neurons_scores.push(Some((neuron, score)));
+        self.n_neurons += 1;
+        self.info.insert(idx, NeuronInfo::default());
         idx
     }
 
+    /// As [add_neuron](#method.add_neuron), also giving the neuron a name
+    /// which can be used instead of its index.
+    /// Names must be unique within the arena.
+    pub fn add_named_neuron(&mut self, name: &str, neuron: N) -> Result<NeuronIdx, Error> {
+        if self.names.contains_key(name) {
+            return Err(Error::DuplicateName(name.to_owned()));
+        }
+        let idx = self.add_neuron(neuron);
+        self.names.insert(name.to_owned(), idx);
+        if let Some(info) = self.info.get_mut(&idx) {
+            info.name = Some(name.to_owned());
+        }
+        Ok(idx)
+    }
+
+    /// Remove a neuron from the arena, returning it if it was present.
+    /// Other neurons' indices are not affected.
+    /// Its name, if any, can then be reused.
+    pub fn remove_neuron(&mut self, idx: NeuronIdx) -> Option<N> {
+        if let Some(name) = self.info.remove(&idx).and_then(|info| info.name) {
+            self.names.remove(&name);
+        }
+        let (neuron, _) = self.neurons_scores.get_mut(idx)?.take()?;
+        self.n_neurons -= 1;
+        Some(neuron)
+    }
+
+    /// Replace a neuron, e.g. after it has been revised, keeping its index, name and metadata,
+    /// and recalculating its self-hit score.
+    /// Returns the old neuron.
+    pub fn replace_neuron(&mut self, idx: NeuronIdx, neuron: N) -> Result<N, Error> {
+        let score = neuron.self_hit(self.use_alpha, &self.score_fn);
+        match self.neurons_scores.get_mut(idx) {
+            Some(Some(ns)) => Ok(std::mem::replace(ns, (neuron, score)).0),
+            _ => Err(Error::MissingNeuron(idx)),
+        }
+    }
+
+    /// Index of the neuron with the given name.
+    pub fn idx_by_name(&self, name: &str) -> Option<NeuronIdx> {
+        self.names.get(name).cloned()
+    }
+
+    /// Name of the neuron with the given index, if it has one.
+    pub fn name(&self, idx: NeuronIdx) -> Option<&str> {
+        self.info.get(&idx)?.name.as_deref()
+    }
+
+    /// Set a metadata value of a neuron, e.g. `("cell_type", "KC")`,
+    /// returning the previous value for that key.
+    pub fn set_metadata(
+        &mut self,
+        idx: NeuronIdx,
+        key: &str,
+        value: &str,
+    ) -> Result<Option<String>, Error> {
+        let info = self.info.get_mut(&idx).ok_or(Error::MissingNeuron(idx))?;
+        Ok(info.metadata.insert(key.to_owned(), value.to_owned()))
+    }
+
+    /// All metadata of a neuron, or `None` if the neuron is not in the arena.
+    pub fn metadata(&self, idx: NeuronIdx) -> Option<&HashMap<String, String>> {
+        self.info.get(&idx).map(|info| &info.metadata)
+    }
+
+    /// Indices of the neurons whose metadata has every one of the given key/value pairs,
+    /// in ascending order.
+    pub fn ids_matching(&self, filter: &[(&str, &str)]) -> Vec<NeuronIdx> {
+        self.ids()
+            .filter(|idx| {
+                // a neuron without info has empty metadata
+                let info = self.info.get(idx);
+                filter.iter().all(|(k, v)| {
+                    info.and_then(|i| i.metadata.get(*k)).map(String::as_str) == Some(*v)
+                })
+            })
+            .collect()
+    }
+
+    /// Whether the arena contains a neuron with the given index.
+    pub fn contains(&self, idx: NeuronIdx) -> bool {
+        self.neuron_score(idx).is_some()
+    }
+
+    /// Indices of the neurons in the arena, in ascending order.
+    pub fn ids(&self) -> impl Iterator<Item = NeuronIdx> + '_ {
+        self.neurons_scores
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, ns)| ns.as_ref().map(|_| idx))
+    }
+
     /// Make a single query using the given indexes.
     /// `normalize` divides the result by the self-hit score of the query neuron.
     /// `symmetry`, if `Some`, also calculates the reverse score
@@ -515,17 +1846,17 @@ where
         target_idx: NeuronIdx,
         normalize: bool,
         symmetry: &Option<Symmetry>,
-    ) -> Option<Precision> {
+    ) -> Option<T> {
         // ? consider separate methods
-        let q = self.neurons_scores.get(query_idx)?;
-        let t = self.neurons_scores.get(target_idx)?;
-        let mut score = q.0.query(&t.0, &self.score_fn);
+        let q = self.neuron_score(query_idx)?;
+        let t = self.neuron_score(target_idx)?;
+        let mut score = self.query_neurons(&q.0, &t.0);
         if normalize {
             score /= q.1;
         }
         match symmetry {
             Some(s) => {
-                let mut score2 = t.0.query(&q.0, &self.score_fn);
+                let mut score2 = self.query_neurons(&t.0, &q.0);
                 if normalize {
                     score2 /= t.1;
                 }
@@ -535,6 +1866,164 @@ where
         }
     }
 
+    /// "Mirror NBLAST": as [query_target](#method.query_target),
+    /// but compare the query with a copy of the target moved by `mirror`,
+    /// e.g. to pair homologous neurons from the left and right hemispheres.
+    /// Any [Transform](struct.Transform.html) can be used,
+    /// although alpha values are only exact for mirrors and rigid transformations.
+    ///
+    /// The transformed target is built with an R*-tree for each call.
+    /// Returns `None` if either neuron is not in the arena,
+    /// or the transformed target is not valid, e.g. if its points are too large to represent.
+    pub fn query_target_mirrored(
+        &self,
+        query_idx: NeuronIdx,
+        target_idx: NeuronIdx,
+        mirror: &Transform<T>,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> Option<T> {
+        let q = self.neuron_score(query_idx)?;
+        let t = self.neuron_score(target_idx)?;
+        let mirrored: RStarPointTangents<T> = mirror.transform_neuron(&t.0).ok()?;
+        let mut score = self.query_neurons(&q.0, &mirrored);
+        if normalize {
+            score /= q.1;
+        }
+        match symmetry {
+            Some(s) => {
+                // the self-hit depends only on alpha values, which are kept
+                let mut score2 = self.query_neurons(&mirrored, &q.0);
+                if normalize {
+                    score2 /= t.1;
+                }
+                Some(apply_symmetry(s, score, score2))
+            }
+            _ => Some(score),
+        }
+    }
+
+    /// As [query_target](#method.query_target),
+    /// but first align the query to the target with a rigid [Icp](struct.Icp.html),
+    /// e.g. to correct small offsets between imperfectly registered brains.
+    /// Returns the score of the aligned query, and the transformation
+    /// which moves the query onto the target.
+    ///
+    /// The aligned query is built with an R*-tree for each call.
+    /// Returns `None` if either neuron is not in the arena,
+    /// or the aligned query is not valid.
+    pub fn query_target_aligned(
+        &self,
+        query_idx: NeuronIdx,
+        target_idx: NeuronIdx,
+        icp: &Icp<T>,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> Option<(T, Transform<T>)> {
+        let q = self.neuron_score(query_idx)?;
+        let t = self.neuron_score(target_idx)?;
+        let transform = icp.align(&q.0, &t.0);
+        let aligned: RStarPointTangents<T> = transform.transform_neuron(&q.0).ok()?;
+        let mut score = self.query_neurons(&aligned, &t.0);
+        if normalize {
+            score /= q.1;
+        }
+        let score = match symmetry {
+            Some(s) => {
+                let mut score2 = self.query_neurons(&t.0, &aligned);
+                if normalize {
+                    score2 /= t.1;
+                }
+                apply_symmetry(s, score, score2)
+            }
+            _ => score,
+        };
+        Some((score, transform))
+    }
+
+    /// Make a single asymmetric query using the given indexes,
+    /// stopping early if the score cannot reach `threshold`.
+    /// `normalize` divides the result (and the threshold is compared to the result)
+    /// by the self-hit score of the query neuron.
+    /// `max_point_score` must be at least the highest value the score function can return;
+    /// see e.g. [ScoreMatrix::max_score](struct.ScoreMatrix.html#method.max_score).
+    /// If `None`, it is taken from the arena's [score function record](#method.score_fn_record).
+    ///
+    /// Fails if either neuron is not in the arena,
+    /// or if `max_point_score` is `None` and the score function is not recorded.
+    pub fn query_target_bounded(
+        &self,
+        query_idx: NeuronIdx,
+        target_idx: NeuronIdx,
+        normalize: bool,
+        threshold: T,
+        max_point_score: Option<T>,
+    ) -> Result<BoundedScore<T>, Error> {
+        let max_point_score = max_point_score
+            .or_else(|| {
+                self.score_fn_record
+                    .as_ref()
+                    .and_then(ScoreFnRecord::max_score)
+                    .map(T::from_precision)
+            })
+            .ok_or(Error::InvalidParameter(
+                "No maximum point score given or recorded",
+            ))?;
+        let q = self
+            .neuron_score(query_idx)
+            .ok_or(Error::MissingNeuron(query_idx))?;
+        let t = self
+            .neuron_score(target_idx)
+            .ok_or(Error::MissingNeuron(target_idx))?;
+        let target = MaxDistTarget {
+            target: &t.0,
+            max_dist: self.max_dist,
+        };
+        if !normalize {
+            return Ok(q.0.query_bounded(
+                &target,
+                self.use_alpha,
+                &self.score_fn,
+                threshold,
+                max_point_score,
+            ));
+        }
+        if q.1 <= T::zero() {
+            // dividing by the self-hit would flip the bound, so just calculate it
+            let score = self.query_neurons(&q.0, &t.0) / q.1;
+            return Ok(if score < threshold {
+                BoundedScore::BelowThreshold
+            } else {
+                BoundedScore::Exact(score)
+            });
+        }
+        Ok(
+            match q.0.query_bounded(
+                &target,
+                self.use_alpha,
+                &self.score_fn,
+                threshold * q.1,
+                max_point_score,
+            ) {
+                BoundedScore::Exact(s) => BoundedScore::Exact(s / q.1),
+                BoundedScore::BelowThreshold => BoundedScore::BelowThreshold,
+            },
+        )
+    }
+
+    /// The details of every point match from the query neuron to the target neuron,
+    /// in the order of the query's points.
+    /// Scores are raw, i.e. not normalized.
+    pub fn query_target_matches(
+        &self,
+        query_idx: NeuronIdx,
+        target_idx: NeuronIdx,
+    ) -> Option<Vec<PointMatch<T>>> {
+        let q = self.neuron_score(query_idx)?;
+        let t = self.neuron_score(target_idx)?;
+        Some(q.0.query_matches(&t.0, self.use_alpha, &self.score_fn))
+    }
+
     /// Make many queries using the Cartesian product of the query and target indices.
     /// See [query_target](#method.query_target) for more details.
     pub fn queries_targets(
@@ -543,7 +2032,7 @@ where
         target_idxs: &[NeuronIdx],
         normalize: bool,
         symmetry: &Option<Symmetry>,
-    ) -> HashMap<(NeuronIdx, NeuronIdx), Precision> {
+    ) -> HashMap<(NeuronIdx, NeuronIdx), T> {
         let mut out = HashMap::with_capacity(query_idxs.len() * target_idxs.len());
 
         // ? lots of unnecessary index operations
@@ -552,8 +2041,8 @@ where
                 let key = (*q_idx, *t_idx);
                 if q_idx == t_idx {
                     // if neurons are present and identical, 1.0 or self-hit (always symmetric)
-                    if let Some(ns) = self.neurons_scores.get(*q_idx) {
-                        out.insert(key, if normalize { 1.0 } else { ns.1 });
+                    if let Some(ns) = self.neuron_score(*q_idx) {
+                        out.insert(key, if normalize { T::one() } else { ns.1 });
                     };
                 } else if symmetry.is_some() {
                     // otherwise, if symmetric, use reverse query score if it's in the result set
@@ -577,8 +2066,150 @@ where
         out
     }
 
-    pub fn self_hit(&self, idx: NeuronIdx) -> Option<Precision> {
-        self.neurons_scores.get(idx).map(|(_, s)| *s)
+    /// As [queries_targets](#method.queries_targets),
+    /// with every neuron whose metadata matches `target_filter` as a target;
+    /// see [ids_matching](#method.ids_matching).
+    pub fn queries_targets_where(
+        &self,
+        query_idxs: &[NeuronIdx],
+        target_filter: &[(&str, &str)],
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> HashMap<(NeuronIdx, NeuronIdx), T> {
+        let target_idxs = self.ids_matching(target_filter);
+        self.queries_targets(query_idxs, &target_idxs, normalize, symmetry)
+    }
+
+    /// As [query_target](#method.query_target), using neurons' names.
+    pub fn query_target_named(
+        &self,
+        query_name: &str,
+        target_name: &str,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> Option<T> {
+        self.query_target(
+            self.idx_by_name(query_name)?,
+            self.idx_by_name(target_name)?,
+            normalize,
+            symmetry,
+        )
+    }
+
+    /// As [queries_targets](#method.queries_targets), using neurons' names.
+    /// Names which are not in the arena are absent from the result.
+    pub fn queries_targets_named(
+        &self,
+        query_names: &[&str],
+        target_names: &[&str],
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> HashMap<(String, String), T> {
+        let to_idxs = |names: &[&str]| -> Vec<NeuronIdx> {
+            names.iter().filter_map(|n| self.idx_by_name(n)).collect()
+        };
+        self.queries_targets(&to_idxs(query_names), &to_idxs(target_names), normalize, symmetry)
+            .into_iter()
+            .filter_map(|((q_idx, t_idx), score)| {
+                let q_name = self.name(q_idx)?.to_owned();
+                let t_name = self.name(t_idx)?.to_owned();
+                Some(((q_name, t_name), score))
+            })
+            .collect()
+    }
+
+    /// Find the `k` best-scoring targets for the given query neuron,
+    /// out of all other neurons in the arena.
+    /// Results are sorted by descending score, with ties broken by index.
+    /// Targets scoring below `min_score`, if given, are excluded.
+    /// Only `k` results are kept in memory at a time.
+    /// See [query_target](#method.query_target) for more details.
+    ///
+    /// Returns `None` if the query neuron is not in the arena.
+    pub fn top_k(
+        &self,
+        query_idx: NeuronIdx,
+        k: usize,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+        min_score: Option<T>,
+    ) -> Option<Vec<(NeuronIdx, T)>> {
+        self.neuron_score(query_idx)?;
+        if k == 0 {
+            return Some(Vec::default());
+        }
+        let mut heap = BinaryHeap::with_capacity(k + 1);
+
+        for t_idx in self.ids() {
+            if t_idx == query_idx {
+                continue;
+            }
+            let score = match (
+                self.query_target(query_idx, t_idx, normalize, symmetry),
+                min_score,
+            ) {
+                (Some(s), Some(m)) if s >= m => s,
+                (Some(s), None) if !s.is_nan() => s,
+                _ => continue,
+            };
+            heap.push(Reverse(ScoredTarget { score, idx: t_idx }));
+            if heap.len() > k {
+                heap.pop();
+            }
+        }
+
+        Some(
+            heap.into_sorted_vec()
+                .into_iter()
+                .map(|Reverse(st)| (st.idx, st.score))
+                .collect(),
+        )
+    }
+
+    /// Find the `k` best-scoring targets for each of the given query neurons.
+    /// Queries which are not in the arena are absent from the result.
+    /// See [top_k](#method.top_k) for more details.
+    pub fn queries_top_k(
+        &self,
+        query_idxs: &[NeuronIdx],
+        k: usize,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+        min_score: Option<T>,
+    ) -> HashMap<NeuronIdx, Vec<(NeuronIdx, T)>> {
+        query_idxs
+            .iter()
+            .filter_map(|q_idx| {
+                self.top_k(*q_idx, k, normalize, symmetry, min_score)
+                    .map(|v| (*q_idx, v))
+            })
+            .collect()
+    }
+
+    pub(crate) fn neuron(&self, idx: NeuronIdx) -> Option<&N> {
+        self.neuron_score(idx).map(|(n, _)| n)
+    }
+
+    /// Whether point matches are weighted by alpha.
+    pub fn use_alpha(&self) -> bool {
+        self.use_alpha
+    }
+
+    /// Description of the score function, if the arena was built or loaded from one.
+    pub fn score_fn_record(&self) -> Option<&ScoreFnRecord> {
+        self.score_fn_record.as_ref()
+    }
+
+    /// Describe the score function, e.g. as a [custom](enum.ScoreFnRecord.html#variant.Custom)
+    /// function for an arena created with [new](#method.new).
+    /// The record is not checked against the score function:
+    /// it is what [save](#method.save) writes and [load_recorded](#method.load_recorded) recreates.
+    pub fn set_score_fn_record(&mut self, record: Option<ScoreFnRecord>) {
+        self.score_fn_record = record;
+    }
+
+    pub fn self_hit(&self, idx: NeuronIdx) -> Option<T> {
+        self.neuron_score(idx).map(|(_, s)| *s)
     }
 
     /// Query every neuron against every other neuron.
@@ -587,56 +2218,222 @@ where
         &self,
         normalize: bool,
         symmetry: &Option<Symmetry>,
-    ) -> HashMap<(NeuronIdx, NeuronIdx), Precision> {
-        let idxs: Vec<NeuronIdx> = (0..self.len()).collect();
+    ) -> HashMap<(NeuronIdx, NeuronIdx), T> {
+        let idxs: Vec<NeuronIdx> = self.ids().collect();
         self.queries_targets(&idxs, &idxs, normalize, symmetry)
     }
 
     pub fn is_empty(&self) -> bool {
-        self.neurons_scores.is_empty()
+        self.len() == 0
     }
 
-    /// Number of neurons in the arena.
+    /// Number of neurons in the arena, not including those which have been removed.
     pub fn len(&self) -> usize {
-        self.neurons_scores.len()
+        self.n_neurons
     }
 
-    pub fn points(&self, idx: NeuronIdx) -> Option<Vec<[Precision; 3]>> {
-        self.neurons_scores.get(idx).map(|(n, _)| n.points())
+    pub fn points(&self, idx: NeuronIdx) -> Option<Vec<[T; 3]>> {
+        self.neuron_score(idx).map(|(n, _)| n.points())
     }
 
-    pub fn tangents(&self, idx: NeuronIdx) -> Option<Vec<Unit<Vector3<Precision>>>> {
-        self.neurons_scores.get(idx).map(|(n, _)| n.tangents())
+    pub fn tangents(&self, idx: NeuronIdx) -> Option<Vec<Unit<Vector3<T>>>> {
+        self.neuron_score(idx).map(|(n, _)| n.tangents())
+    }
+
+    pub fn alphas(&self, idx: NeuronIdx) -> Option<Vec<T>> {
+        self.neuron_score(idx).map(|(n, _)| n.alphas())
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+impl<N, T> NblastArena<N, BoxedScoreFn<T>, T>
+where
+    N: TargetNeuron<T>,
+    T: Float,
+{
+    /// Use the score matrix as the score function.
+    /// If the outermost distance bin has the same score for every dot product,
+    /// also [stop searching](#method.set_max_dist) for each query point's nearest target point
+    /// beyond that bin's lower bound, which does not change any scores:
+    /// see [ScoreMatrix::max_dist](struct.ScoreMatrix.html#method.max_dist).
+    ///
+    /// Tables whose outermost row depends on the dot product,
+    /// like the FlyCircuit table, never get a cutoff here;
+    /// see [from_score_matrix_with_cutoff](#method.from_score_matrix_with_cutoff).
+    pub fn from_score_matrix(smat: ScoreMatrix, use_alpha: bool) -> Self {
+        let max_dist = smat.max_dist().map(T::from_precision);
+        Self::from_table(smat, use_alpha, max_dist)
+    }
 
-    const EPSILON: Precision = 0.001;
-    const N_NEIGHBORS: usize = 5;
+    /// Use the score matrix as the score function,
+    /// and stop searching for each query point's nearest target point
+    /// beyond the lower bound of the outermost distance bin,
+    /// whether or not that bin's score depends on the dot product.
+    ///
+    /// This changes scores: point matches beyond the cutoff score as
+    /// [DistDot::out_of_range](struct.DistDot.html#method.out_of_range),
+    /// i.e. the outermost bin's score for a dot product of 0,
+    /// rather than by their real dot product.
+    /// With a single distance bin, there is no cutoff.
+    pub fn from_score_matrix_with_cutoff(smat: ScoreMatrix, use_alpha: bool) -> Self {
+        let max_dist = smat.outermost_dist().map(T::from_precision);
+        Self::from_table(smat, use_alpha, max_dist)
+    }
 
-    fn add_points(a: &[f64; 3], b: &[f64; 3]) -> [f64; 3] {
-        let mut out = [0., 0., 0.];
-        for (idx, (x, y)) in a.iter().zip(b.iter()).enumerate() {
-            out[idx] = x + y;
-        }
-        out
+    fn from_table(smat: ScoreMatrix, use_alpha: bool, max_dist: Option<T>) -> Self {
+        let mut arena = Self::new(Box::new(smat.clone().to_fn()), use_alpha);
+        arena.score_fn_record = Some(ScoreFnRecord::Table(smat));
+        arena.set_max_dist(max_dist);
+        arena
+    }
+
+    /// Use the score matrix as the score function,
+    /// bilinearly interpolating between bin centres:
+    /// see [ScoreMatrix::to_interp_fn](struct.ScoreMatrix.html#method.to_interp_fn).
+    /// No maximum search distance is set.
+    pub fn from_interp_score_matrix(smat: ScoreMatrix, use_alpha: bool) -> Self {
+        let mut arena = Self::new(Box::new(smat.clone().to_interp_fn()), use_alpha);
+        arena.score_fn_record = Some(ScoreFnRecord::InterpolatedTable(smat));
+        arena
+    }
+
+    /// Use the parametric score as the score function.
+    pub fn from_parametric(score: ParametricScore, use_alpha: bool) -> Self {
+        let mut arena = Self::new(Box::new(score.to_fn()), use_alpha);
+        arena.score_fn_record = Some(ScoreFnRecord::Parametric(score));
+        arena
     }
+}
 
-    fn make_points(offset: &[f64; 3], step: &[f64; 3], count: usize) -> Vec<[f64; 3]> {
-        let mut out = Vec::default();
-        out.push(*offset);
+#[cfg(feature = "parallel")]
+impl<N, F, T> NblastArena<N, F, T>
+where
+    N: TargetNeuron<T> + Sync,
+    F: Fn(&DistDot<T>) -> T + Sync,
+    T: Float,
+{
+    /// Set the number of threads used by the parallel query methods,
+    /// building a thread pool which is reused for every query.
+    /// If `None` (the default), rayon's global thread pool is used.
+    pub fn set_threads(&mut self, threads: Option<usize>) -> Result<(), Error> {
+        self.thread_pool = match threads {
+            Some(n) => Some(Arc::new(
+                rayon::ThreadPoolBuilder::new().num_threads(n).build()?,
+            )),
+            None => None,
+        };
+        Ok(())
+    }
 
-        for _ in 0..count - 1 {
-            let to_push = add_points(out.last().unwrap(), step);
-            out.push(to_push);
+    /// Run the closure in the arena's thread pool, or the global pool if there is none.
+    fn install<OP, R>(&self, op: OP) -> R
+    where
+        OP: FnOnce() -> R + Send,
+        R: Send,
+    {
+        match &self.thread_pool {
+            Some(pool) => pool.install(op),
+            None => op(),
         }
+    }
 
+    /// Make many queries using the Cartesian product of the query and target indices,
+    /// spreading them across a thread pool.
+    /// Results are identical to [queries_targets](#method.queries_targets).
+    pub fn queries_targets_par(
+        &self,
+        query_idxs: &[NeuronIdx],
+        target_idxs: &[NeuronIdx],
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> HashMap<(NeuronIdx, NeuronIdx), T> {
+        // symmetric queries only need to be calculated in one direction
+        let mut pairs = HashSet::with_capacity(query_idxs.len() * target_idxs.len());
+        for q_idx in query_idxs.iter() {
+            for t_idx in target_idxs.iter() {
+                if q_idx == t_idx {
+                    continue;
+                }
+                if symmetry.is_some() && q_idx > t_idx {
+                    pairs.insert((*t_idx, *q_idx));
+                } else {
+                    pairs.insert((*q_idx, *t_idx));
+                }
+            }
+        }
+        let pairs: Vec<_> = pairs.into_iter().collect();
+
+        let scores: HashMap<_, _> = self.install(|| {
+            pairs
+                .par_iter()
+                .filter_map(|(q_idx, t_idx)| {
+                    self.query_target(*q_idx, *t_idx, normalize, symmetry)
+                        .map(|s| ((*q_idx, *t_idx), s))
+                })
+                .collect()
+        });
+
+        let mut out = HashMap::with_capacity(query_idxs.len() * target_idxs.len());
+        for q_idx in query_idxs.iter() {
+            for t_idx in target_idxs.iter() {
+                let key = (*q_idx, *t_idx);
+                let score = if q_idx == t_idx {
+                    self.self_hit(*q_idx)
+                        .map(|s| if normalize { T::one() } else { s })
+                } else if symmetry.is_some() && q_idx > t_idx {
+                    scores.get(&(*t_idx, *q_idx)).cloned()
+                } else {
+                    scores.get(&key).cloned()
+                };
+                if let Some(s) = score {
+                    out.insert(key, s);
+                }
+            }
+        }
         out
     }
 
+    /// Find the `k` best-scoring targets for each of the given query neurons,
+    /// spreading the queries across a thread pool.
+    /// See [top_k](#method.top_k) for more details.
+    pub fn queries_top_k_par(
+        &self,
+        query_idxs: &[NeuronIdx],
+        k: usize,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+        min_score: Option<T>,
+    ) -> HashMap<NeuronIdx, Vec<(NeuronIdx, T)>> {
+        self.install(|| {
+            query_idxs
+                .par_iter()
+                .filter_map(|q_idx| {
+                    self.top_k(*q_idx, k, normalize, symmetry, min_score)
+                        .map(|v| (*q_idx, v))
+                })
+                .collect()
+        })
+    }
+
+    /// Query every neuron against every other neuron, spreading the queries across a thread pool.
+    /// See [queries_targets_par](#method.queries_targets_par) for more details.
+    pub fn all_v_all_par(
+        &self,
+        normalize: bool,
+        symmetry: &Option<Symmetry>,
+    ) -> HashMap<(NeuronIdx, NeuronIdx), T> {
+        let idxs: Vec<NeuronIdx> = self.ids().collect();
+        self.queries_targets_par(&idxs, &idxs, normalize, symmetry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::make_points;
+
+    const EPSILON: Precision = 0.001;
+    const N_NEIGHBORS: usize = 5;
+
     #[test]
     fn construct() {
         let points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
@@ -664,7 +2461,7 @@ mod tests {
     #[test]
     fn unit_tangents_eig() {
         let (points, _) = tangent_data();
-        let tangent = points_to_tangent_eig(points.iter()).expect("eig failed");
+        let (tangent, _) = points_to_tangent_eig(points.iter()).expect("eig failed");
         assert_close(tangent.dot(&tangent), 1.0)
     }
 
@@ -728,7 +2525,7 @@ mod tests {
     #[test]
     fn test_tangent_eig() {
         let (points, expected) = tangent_data();
-        let tangent = points_to_tangent_eig(points.iter()).expect("Failed to create tangent");
+        let (tangent, _) = points_to_tangent_eig(points.iter()).expect("Failed to create tangent");
         if !equivalent_tangents(&tangent, &expected) {
             panic!(
                 "Non-equivalent tangents:\n\t{:?}\n\t{:?}",
@@ -752,7 +2549,7 @@ mod tests {
     #[test]
     fn test_score_fn() {
         let (dists, dots, values) = score_mat();
-        let func = table_to_fn(dists, dots, values);
+        let func = table_to_fn(dists, dots, values).expect("Invalid table");
         assert_close(
             func(&DistDot {
                 dist: 0.0,
@@ -808,6 +2605,244 @@ mod tests {
     //     assert_eq!(find_bin_linear(0.1, &dots), 1);
     // }
 
+    #[test]
+    fn radius_neighborhood() {
+        // dense along x, sparse along y
+        let mut points = make_points(&[0., 0., 0.], &[0.1, 0., 0.], 20);
+        points.extend(make_points(&[0., 10., 0.], &[0., 5., 0.], 5));
+        let neighborhood = Neighborhood::Radius {
+            radius: 0.35,
+            min_count: 3,
+        };
+        let neuron = RStarPointTangents::new_with_neighborhood(points.clone(), &neighborhood)
+            .expect("Construction failed");
+        let tangents = neuron.tangents();
+        let alphas = neuron.alphas();
+        assert!(tangents[10][0].abs() > 0.99);
+        assert!(alphas[10] > 0.99);
+        // sparse points have no others within the radius, so fall back to the nearest
+        assert!(tangents[22][1].abs() > 0.99);
+
+        let bad = Neighborhood::Radius {
+            radius: -1.0,
+            min_count: 3,
+        };
+        assert!(RStarPointTangents::new_with_neighborhood(points, &bad).is_err());
+    }
+
+    #[test]
+    fn degenerate_neighborhoods() {
+        let mut points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        // 3 copies of the same point far away from the rest
+        points.extend(vec![[0.1, 50., 50.]; 3]);
+        let nbhd = Neighborhood::Nearest(3);
+
+        match RStarPointTangents::new_with_neighborhood(points.clone(), &nbhd) {
+            Err(Error::DegenerateNeighborhood { idx }) => assert_eq!(idx, 10),
+            _ => panic!("Expected DegenerateNeighborhood"),
+        }
+
+        let (skipped, degenerate) =
+            RStarPointTangents::new_with_policy(points.clone(), &nbhd, DegeneratePolicy::Skip)
+                .expect("Construction failed");
+        assert_eq!(degenerate, vec![10, 11, 12]);
+        assert_eq!(skipped.len(), 10);
+        assert_eq!(skipped.points(), points[..10].to_vec());
+
+        let (widened, degenerate) = QueryPointTangents::new_with_policy(
+            points.clone(),
+            &nbhd,
+            DegeneratePolicy::Widen { max_count: 10 },
+        )
+        .expect("Construction failed");
+        assert_eq!(degenerate.len(), 3);
+        assert_eq!(widened.len(), 13);
+        assert!(widened.tangents().iter().all(|t| t.iter().all(|v| v.is_finite())));
+        assert!(QueryPointTangents::new_with_policy(
+            points.clone(),
+            &nbhd,
+            DegeneratePolicy::Widen { max_count: 3 },
+        )
+        .is_err());
+
+        let (inherited, _) =
+            RStarPointTangents::new_with_policy(points.clone(), &nbhd, DegeneratePolicy::Inherit)
+                .expect("Construction failed");
+        assert_eq!(inherited.tangents()[12], inherited.tangents()[0]);
+        assert_eq!(inherited.alphas()[12], inherited.alphas()[0]);
+    }
+
+    #[test]
+    fn degenerate_tolerance() {
+        // coincident in single precision, but not in double precision
+        let points = make_points(&[0., 0., 0.], &[1e-7, 0., 0.], 3);
+        assert!(points_to_tangent_eig(points.iter()).is_some());
+        let points32: Vec<[f32; 3]> = points
+            .iter()
+            .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+            .collect();
+        assert!(points_to_tangent_eig(points32.iter()).is_none());
+    }
+
+    #[test]
+    fn inherit_from_non_degenerate() {
+        // two lines, and two adjacent clusters of duplicates between them:
+        // the first is nearer the end of the x line, the second nearer the start of the y line
+        let mut points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        points.extend(make_points(&[30., 0., 0.], &[0., 1., 0.], 10));
+        points.extend(vec![[19., 0., 0.]; 3]);
+        points.extend(vec![[21., 0., 0.]; 3]);
+        let (inherited, degenerate) = RStarPointTangents::new_with_policy(
+            points,
+            &Neighborhood::Nearest(3),
+            DegeneratePolicy::Inherit,
+        )
+        .expect("Construction failed");
+        assert_eq!(degenerate, (20..26).collect::<Vec<_>>());
+        let tangents = inherited.tangents();
+        assert_eq!(tangents[20], tangents[9]);
+        assert_eq!(tangents[23], tangents[10]);
+        assert!(tangents[23][1].abs() > 0.99);
+    }
+
+    #[test]
+    fn tangents_from_parents() {
+        // two branches crossing close together
+        let mut points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        points.extend(make_points(&[4.5, -4.5, 0.1], &[0., 1., 0.], 10));
+        let parents: Vec<_> = (0..20)
+            .map(|idx| match idx {
+                0 | 10 => None,
+                _ => Some(idx - 1),
+            })
+            .collect();
+        let neuron =
+            RStarPointTangents::new_with_parents(points.clone(), &parents).expect("Construction failed");
+        let tangents = neuron.tangents();
+        assert_eq!(tangents[0], Unit::new_normalize(Vector3::new(1., 0., 0.)));
+        assert_eq!(tangents[4], Unit::new_normalize(Vector3::new(1., 0., 0.)));
+        assert_eq!(tangents[14], Unit::new_normalize(Vector3::new(0., 1., 0.)));
+
+        let mut bad_parents = parents.clone();
+        bad_parents[1] = Some(20);
+        assert!(QueryPointTangents::new_with_parents(points.clone(), &bad_parents).is_err());
+        let mut coincident = points[..2].to_vec();
+        coincident[1] = coincident[0];
+        match QueryPointTangents::new_with_parents(coincident, &parents[..2]) {
+            Err(Error::DegenerateNeighborhood { idx }) => assert_eq!(idx, 0),
+            _ => panic!("Expected DegenerateNeighborhood"),
+        }
+    }
+
+    #[test]
+    fn zero_length_edges() {
+        let x = Unit::new_normalize(Vector3::new(1., 0., 0.));
+        let y = Unit::new_normalize(Vector3::new(0., 1., 0.));
+        let chain = vec![None, Some(0), Some(1)];
+
+        // leaf at the same position as its parent
+        let leaf = QueryPointTangents::new_with_parents(
+            vec![[0., 0., 0.], [1., 0., 0.], [1., 0., 0.]],
+            &chain,
+        )
+        .expect("Construction failed");
+        assert_eq!(leaf.tangents()[2], x);
+
+        // root at the same position as its child
+        let root = QueryPointTangents::new_with_parents(
+            vec![[0., 0., 0.], [0., 0., 0.], [0., 1., 0.]],
+            &chain,
+        )
+        .expect("Construction failed");
+        assert_eq!(root.tangents(), vec![y; 3]);
+
+        // isolated root
+        let points = vec![[0., 0., 0.], [1., 0., 0.], [2., 0., 0.], [5., 5., 5.]];
+        let mut parents = chain.clone();
+        parents.push(None);
+        match RStarPointTangents::new_with_parents(points.clone(), &parents) {
+            Err(Error::DegenerateNeighborhood { idx }) => assert_eq!(idx, 3),
+            _ => panic!("Expected DegenerateNeighborhood"),
+        }
+        let (skipped, degenerate) =
+            RStarPointTangents::new_with_parents_policy(points.clone(), &parents, DegeneratePolicy::Skip)
+                .expect("Construction failed");
+        assert_eq!(degenerate, vec![3]);
+        assert_eq!(skipped.points(), points[..3].to_vec());
+        let (inherited, _) = QueryPointTangents::new_with_parents_policy(
+            points.clone(),
+            &parents,
+            DegeneratePolicy::Inherit,
+        )
+        .expect("Construction failed");
+        assert_eq!(inherited.tangents(), vec![x; 4]);
+        assert!(QueryPointTangents::new_with_parents_policy(
+            points,
+            &parents,
+            DegeneratePolicy::Widen { max_count: 10 },
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn invalid_neurons() {
+        let points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        match RStarPointTangents::new(points[..3].to_vec(), N_NEIGHBORS) {
+            Err(Error::TooFewPoints { required, actual }) => {
+                assert_eq!((required, actual), (N_NEIGHBORS, 3))
+            }
+            _ => panic!("Expected TooFewPoints"),
+        }
+        match RStarPointTangents::<Precision>::new(Vec::default(), 0) {
+            Err(Error::EmptyTarget) => (),
+            _ => panic!("Expected EmptyTarget"),
+        }
+        let mut bad_points = points.clone();
+        bad_points[2][1] = (-1.0 as Precision).sqrt();
+        match QueryPointTangents::new(bad_points, N_NEIGHBORS) {
+            Err(Error::NonFinite { idx }) => assert_eq!(idx, 2),
+            _ => panic!("Expected NonFinite"),
+        }
+        let tangents = vec![Unit::new_normalize(Vector3::new(1., 0., 0.)); 9];
+        match RStarPointTangents::new_with_tangents(points, tangents) {
+            Err(Error::MismatchedLengths { points, other }) => assert_eq!((points, other), (10, 9)),
+            _ => panic!("Expected MismatchedLengths"),
+        }
+        assert!(table_to_fn::<Precision>(vec![1.0], vec![1.0], vec![1.0, 2.0]).is_err());
+    }
+
+    fn expect_empty<N>(result: Result<N, Error>) {
+        match result {
+            Err(Error::EmptyTarget) => (),
+            _ => panic!("Expected EmptyTarget"),
+        }
+    }
+
+    /// Every target constructor rejects empty neurons,
+    /// so nearest-neighbor searches without a maximum distance always find a point.
+    #[test]
+    fn empty_targets() {
+        let empty: Vec<[Precision; 3]> = Vec::default();
+        expect_empty(KdTreePointTangents::new(empty.clone(), 0));
+        expect_empty(GridPointTangents::new_with_neighborhood(
+            empty.clone(),
+            &Neighborhood::Nearest(0),
+        ));
+        expect_empty(BruteForcePointTangents::new_with_parents(empty.clone(), &[]));
+        expect_empty(RStarPointTangents::new_with_tangents(empty.clone(), Vec::default()));
+        expect_empty(DistanceFieldPointTangents::new_with_tangents_alphas(
+            empty,
+            Vec::default(),
+            Vec::default(),
+        ));
+        // every point skipped
+        expect_empty(RStarPointTangents::new_with_policy(
+            vec![[1., 2., 3.]; 4],
+            &Neighborhood::Nearest(3),
+            DegeneratePolicy::Skip,
+        ));
+    }
+
     #[test]
     fn test_find_bin_binary() {
         let dots = vec![0.1, 0.2, 0.3, 0.4, 0.5, 0.6, 0.7, 0.8, 0.9, 1.0];
@@ -817,6 +2852,72 @@ mod tests {
         assert_eq!(find_bin_binary(-10.0, &dots), 0);
         assert_eq!(find_bin_binary(10.0, &dots), 9);
         assert_eq!(find_bin_binary(0.1, &dots), 1);
+        assert_eq!(find_bin_binary((-1.0 as Precision).sqrt(), &dots), 9);
+    }
+
+    #[test]
+    fn nan_scores_worst() {
+        let (dists, dots, values) = score_mat();
+        let smat = ScoreMatrix::new(dists, dots, values).expect("Invalid table");
+        let check = |func: &dyn Fn(&DistDot) -> Precision| {
+            let nan = Precision::NAN;
+            assert_close(func(&DistDot { dist: nan, dot: 1.0 }), 49.0);
+            assert_close(func(&DistDot { dist: 0.0, dot: nan }), 0.0);
+            assert_close(func(&DistDot { dist: nan, dot: nan }), 40.0);
+        };
+        check(&smat.clone().to_fn());
+        check(&smat.to_interp_fn());
+    }
+
+    #[test]
+    fn test_find_interp_bins() {
+        let dists = vec![1.0, 2.0, 3.0];
+        assert_eq!(find_interp_bins(0.0, &dists), (0, 0, 0.0));
+        assert_eq!(find_interp_bins(0.5, &dists), (0, 0, 0.0));
+        assert_eq!(find_interp_bins(1.0, &dists), (0, 1, 0.5));
+        assert_eq!(find_interp_bins(1.5, &dists), (1, 2, 0.0));
+        assert_eq!(find_interp_bins(3.0, &dists), (2, 2, 0.0));
+    }
+
+    #[test]
+    fn test_interp_score_fn() {
+        let (dists, dots, values) = score_mat();
+        let func = table_to_interp_fn(dists, dots, values).expect("Invalid table");
+        assert_close(
+            func(&DistDot {
+                dist: 0.0,
+                dot: 0.0,
+            }),
+            0.0,
+        );
+        assert_close(
+            func(&DistDot {
+                dist: 15.0,
+                dot: 0.15,
+            }),
+            11.0,
+        );
+        assert_close(
+            func(&DistDot {
+                dist: 10.0,
+                dot: 0.1,
+            }),
+            5.5,
+        );
+        assert_close(
+            func(&DistDot {
+                dist: 15.0,
+                dot: 0.2,
+            }),
+            11.5,
+        );
+        assert_close(
+            func(&DistDot {
+                dist: 100.0,
+                dot: 10.0,
+            }),
+            49.0,
+        );
     }
 
     #[test]
@@ -825,7 +2926,7 @@ mod tests {
         let dot_thresholds = vec![0.5, 1.0];
         let cells = vec![1.0, 2.0, 4.0, 8.0];
 
-        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells);
+        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells).expect("Invalid table");
 
         let q_points = make_points(&[0., 0., 0.], &[1.0, 0.0, 0.0], 10);
         let query = QueryPointTangents::new(q_points.clone(), N_NEIGHBORS).expect("Query construction failed");
@@ -834,14 +2935,14 @@ mod tests {
             .expect("Construction failed");
 
         assert_close(
-            query.query(&target, &score_fn),
-            query2.query(&target, &score_fn),
+            query.query(&target, false, &score_fn),
+            query2.query(&target, false, &score_fn),
         );
-        assert_close(query.self_hit(&score_fn), query2.self_hit(&score_fn));
-        let score = query.query(&query2, &score_fn);
-        let self_hit = query.self_hit(&score_fn);
+        assert_close(query.self_hit(false, &score_fn), query2.self_hit(false, &score_fn));
+        let score = query.query(&query2, false, &score_fn);
+        let self_hit = query.self_hit(false, &score_fn);
         println!("score: {:?}, self-hit {:?}", score, self_hit);
-        assert_close(query.query(&query2, &score_fn), query.self_hit(&score_fn));
+        assert_close(query.query(&query2, false, &score_fn), query.self_hit(false, &score_fn));
     }
 
     #[test]
@@ -850,14 +2951,14 @@ mod tests {
         let dot_thresholds = vec![0.5, 1.0];
         let cells = vec![1.0, 2.0, 4.0, 8.0];
 
-        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells);
+        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells).expect("Invalid table");
 
         let query = RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
             .expect("Construction failed");
         let target = RStarPointTangents::new(make_points(&[0.5, 0., 0.], &[1.1, 0., 0.], 10), N_NEIGHBORS)
             .expect("Construction failed");
 
-        let mut arena = NblastArena::new(score_fn);
+        let mut arena = NblastArena::new(score_fn, false);
         let q_idx = arena.add_neuron(query);
         let t_idx = arena.add_neuron(target);
 
@@ -884,6 +2985,550 @@ mod tests {
         assert_eq!(out.len(), 4);
     }
 
+    #[test]
+    fn remove_replace() {
+        let score_fn = |dd: &DistDot| dd.dot - dd.dist;
+        let mut arena = NblastArena::new(score_fn, false);
+        let idxs: Vec<_> = (0..4)
+            .map(|i| {
+                let points = make_points(&[0., i as Precision, 0.], &[1., 0., 0.], 10 + i);
+                let neuron = RStarPointTangents::new(points, N_NEIGHBORS).expect("Construction failed");
+                arena.add_neuron(neuron)
+            })
+            .collect();
+        let before = arena.query_target(idxs[2], idxs[3], false, &None);
+
+        let removed = arena.remove_neuron(idxs[1]).expect("should exist");
+        assert_eq!(removed.len(), 11);
+        assert!(arena.remove_neuron(idxs[1]).is_none());
+        assert!(arena.remove_neuron(100).is_none());
+        assert!(!arena.contains(idxs[1]));
+        assert_eq!(arena.len(), 3);
+        assert_eq!(arena.ids().collect::<Vec<_>>(), vec![idxs[0], idxs[2], idxs[3]]);
+        assert!(arena.query_target(idxs[0], idxs[1], false, &None).is_none());
+        assert_eq!(arena.query_target(idxs[2], idxs[3], false, &None), before);
+        assert_eq!(arena.all_v_all(false, &None).len(), 9);
+
+        // identifiers are not reused
+        let new_idx = arena.add_neuron(removed.clone());
+        assert_eq!(new_idx, 4);
+        assert_eq!(arena.len(), 4);
+
+        let replacement = RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 20), N_NEIGHBORS)
+            .expect("Construction failed");
+        let old = arena
+            .replace_neuron(idxs[0], replacement)
+            .expect("should exist");
+        assert_eq!(old.len(), 10);
+        assert_eq!(arena.points(idxs[0]).map(|p| p.len()), Some(20));
+        assert_close(arena.self_hit(idxs[0]).expect("should exist"), 20.0);
+        match arena.replace_neuron(idxs[1], removed) {
+            Err(Error::MissingNeuron(idx)) => assert_eq!(idx, idxs[1]),
+            _ => panic!("Expected MissingNeuron error"),
+        }
+    }
+
+    #[test]
+    fn named_neurons() {
+        let score_fn = |dd: &DistDot| dd.dot - dd.dist;
+        let mut arena = NblastArena::new(score_fn, false);
+        let neuron = |offset: Precision| {
+            RStarPointTangents::new(make_points(&[0., offset, 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+                .expect("Construction failed")
+        };
+        for (i, (name, cell_type)) in [("a", "KC"), ("b", "PN"), ("c", "KC")].iter().enumerate() {
+            let idx = arena
+                .add_named_neuron(name, neuron(i as Precision))
+                .expect("Could not add neuron");
+            assert_eq!(arena.set_metadata(idx, "cell_type", cell_type).unwrap(), None);
+        }
+        let unnamed = arena.add_neuron(neuron(3.));
+        match arena.add_named_neuron("a", neuron(4.)) {
+            Err(Error::DuplicateName(name)) => assert_eq!(name, "a"),
+            _ => panic!("Expected DuplicateName error"),
+        }
+
+        assert_eq!(arena.idx_by_name("b"), Some(1));
+        assert_eq!(arena.name(1), Some("b"));
+        assert_eq!(arena.name(unnamed), None);
+        assert_eq!(arena.metadata(2).unwrap()["cell_type"], "KC");
+        assert!(arena.metadata(unnamed).unwrap().is_empty());
+        assert_eq!(arena.ids_matching(&[("cell_type", "KC")]), vec![0, 2]);
+        assert_eq!(arena.ids_matching(&[]), vec![0, 1, 2, unnamed]);
+
+        let by_type = arena.queries_targets_where(&[1], &[("cell_type", "KC")], true, &None);
+        let mut keys: Vec<_> = by_type.keys().cloned().collect();
+        keys.sort();
+        assert_eq!(keys, vec![(1, 0), (1, 2)]);
+
+        assert_eq!(
+            arena.query_target_named("a", "c", true, &None),
+            arena.query_target(0, 2, true, &None)
+        );
+        assert!(arena.query_target_named("a", "z", true, &None).is_none());
+        let named = arena.queries_targets_named(&["a", "z"], &["b", "c"], true, &None);
+        assert_eq!(named.len(), 2);
+        assert_eq!(
+            named[&("a".to_owned(), "b".to_owned())],
+            arena.query_target(0, 1, true, &None).expect("should exist")
+        );
+
+        arena.remove_neuron(0);
+        assert_eq!(arena.idx_by_name("a"), None);
+        assert!(arena.metadata(0).is_none());
+        assert_eq!(arena.add_named_neuron("a", neuron(0.)).expect("Name is free"), unnamed + 1);
+        match arena.set_metadata(0, "cell_type", "KC") {
+            Err(Error::MissingNeuron(0)) => (),
+            _ => panic!("Expected MissingNeuron error"),
+        }
+    }
+
+    #[test]
+    fn alpha_colinear() {
+        let points = make_points(&[0., 0., 0.], &[1., 1., 0.], 5);
+        let (_, alpha) = points_to_tangent_eig(points.iter()).expect("eig failed");
+        assert_close(alpha, 1.0);
+
+        let (points, _) = tangent_data();
+        let (_, alpha) = points_to_tangent_eig(points.iter()).expect("eig failed");
+        assert!(alpha > 0.0 && alpha < 1.0);
+    }
+
+    #[test]
+    fn alpha_weighting() {
+        let dist_thresholds = vec![1.0, 2.0];
+        let dot_thresholds = vec![0.5, 1.0];
+        let cells = vec![1.0, 2.0, 4.0, 8.0];
+        let score_fn = table_to_fn(dist_thresholds, dot_thresholds, cells).expect("Invalid table");
+
+        let points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        let tangents = vec![Unit::new_normalize(Vector3::new(1., 0., 0.)); 10];
+        let query = RStarPointTangents::new_with_tangents_alphas(
+            points.clone(),
+            tangents.clone(),
+            vec![0.16; 10],
+        )
+        .expect("Construction failed");
+        let target = RStarPointTangents::new_with_tangents(points, tangents)
+            .expect("Construction failed");
+
+        // dot is scaled from 1.0 to 0.4, which falls in the lower bin
+        assert_close(query.query(&target, false, &score_fn), 20.0);
+        assert_close(query.query(&target, true, &score_fn), 10.0);
+        assert_close(query.self_hit(true, &score_fn), 10.0);
+        assert_close(target.self_hit(true, &score_fn), 20.0);
+    }
+
+    #[test]
+    fn bounded_query() {
+        let score_fn = table_to_fn(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0]).expect("Invalid table");
+        let query = QueryPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+            .expect("Construction failed");
+        let target = RStarPointTangents::new(make_points(&[0.5, 0., 0.], &[1.1, 0., 0.], 10), N_NEIGHBORS)
+            .expect("Construction failed");
+        let score = query.query(&target, false, &score_fn);
+
+        assert_eq!(
+            query.query_bounded(&target, false, &score_fn, score, 8.0),
+            BoundedScore::Exact(score)
+        );
+        assert_eq!(
+            query.query_bounded(&target, false, &score_fn, score + 0.1, 8.0),
+            BoundedScore::BelowThreshold
+        );
+
+        let mut arena = NblastArena::new(score_fn, false);
+        let q_idx = arena.add_neuron(
+            RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+                .expect("Construction failed"),
+        );
+        let t_idx = arena.add_neuron(target);
+        let norm_score = arena
+            .query_target(q_idx, t_idx, true, &None)
+            .expect("should exist");
+        assert_eq!(
+            arena
+                .query_target_bounded(q_idx, t_idx, true, norm_score - EPSILON, Some(8.0))
+                .expect("should exist"),
+            BoundedScore::Exact(norm_score)
+        );
+        assert_eq!(
+            arena
+                .query_target_bounded(q_idx, t_idx, true, norm_score + EPSILON, Some(8.0))
+                .expect("should exist"),
+            BoundedScore::BelowThreshold
+        );
+        match arena.query_target_bounded(q_idx, 10, true, 0.0, Some(8.0)) {
+            Err(Error::MissingNeuron(10)) => (),
+            _ => panic!("Expected MissingNeuron error"),
+        }
+        match arena.query_target_bounded(q_idx, t_idx, true, 0.0, None) {
+            Err(Error::InvalidParameter(_)) => (),
+            _ => panic!("Expected InvalidParameter error"),
+        }
+
+        // the maximum point score is taken from the recorded score matrix
+        let smat = ScoreMatrix::new(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0])
+            .expect("Invalid table");
+        let mut arena: NblastArena<RStarPointTangents, _> =
+            NblastArena::from_score_matrix(smat, false);
+        let q_idx = arena.add_neuron(
+            RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+                .expect("Construction failed"),
+        );
+        let t_idx = arena.add_neuron(
+            RStarPointTangents::new(make_points(&[0.5, 0., 0.], &[1.1, 0., 0.], 10), N_NEIGHBORS)
+                .expect("Construction failed"),
+        );
+        assert_eq!(
+            arena
+                .query_target_bounded(q_idx, t_idx, false, score + 0.1, None)
+                .expect("should exist"),
+            BoundedScore::BelowThreshold
+        );
+        assert_eq!(
+            arena
+                .query_target_bounded(q_idx, t_idx, false, score, None)
+                .expect("should exist"),
+            BoundedScore::Exact(score)
+        );
+    }
+
+    #[test]
+    fn point_matches() {
+        let score_fn = table_to_fn(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0]).expect("Invalid table");
+        let q_points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        let t_points = make_points(&[0.5, 0., 0.], &[1.1, 0., 0.], 10);
+        let query = QueryPointTangents::new(q_points.clone(), N_NEIGHBORS).expect("Construction failed");
+        let query2 = RStarPointTangents::new(q_points.clone(), N_NEIGHBORS).expect("Construction failed");
+        let target = RStarPointTangents::new(t_points.clone(), N_NEIGHBORS).expect("Construction failed");
+
+        let matches = query.query_matches(&target, false, &score_fn);
+        assert_eq!(matches, query2.query_matches(&target, false, &score_fn));
+        // the k-d tree stores its points in a different order
+        let query3 = KdTreePointTangents::new(q_points.clone(), N_NEIGHBORS).expect("Construction failed");
+        assert_eq!(query3.points(), q_points);
+        assert_eq!(matches, query3.query_matches(&target, false, &score_fn));
+        assert_eq!(matches.len(), 10);
+        let total: Precision = matches.iter().map(|pm| pm.score).sum();
+        assert_close(total, query.query(&target, false, &score_fn));
+        for (idx, pm) in matches.iter().enumerate() {
+            assert_eq!(pm.query_idx, idx);
+            let q_pt = q_points[pm.query_idx];
+            let t_pt = t_points[pm.target_idx];
+            assert_close(pm.dist_dot.dist, (q_pt[0] - t_pt[0]).abs());
+        }
+
+        let mut arena = NblastArena::new(score_fn, false);
+        let q_idx = arena.add_neuron(query2);
+        let t_idx = arena.add_neuron(target);
+        assert_eq!(arena.query_target_matches(q_idx, t_idx), Some(matches));
+        assert_eq!(arena.query_target_matches(q_idx, 10), None);
+    }
+
+    #[test]
+    fn stops_early() {
+        let calls = std::cell::Cell::new(0);
+        let score_fn = |_: &DistDot| {
+            calls.set(calls.get() + 1);
+            0.0
+        };
+        let points = make_points(&[0., 0., 0.], &[1., 0., 0.], 10);
+        let query =
+            QueryPointTangents::new(points.clone(), N_NEIGHBORS).expect("Construction failed");
+        let target = RStarPointTangents::new(points, N_NEIGHBORS).expect("Construction failed");
+        // after 3 points scoring 0, the 7 remaining points can only reach 7
+        assert_eq!(
+            query.query_bounded(&target, false, &score_fn, 7.5, 1.0),
+            BoundedScore::BelowThreshold
+        );
+        assert_eq!(calls.get(), 3);
+    }
+
+    #[test]
+    fn top_k() {
+        let score_fn = table_to_fn(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0]).expect("Invalid table");
+        let mut arena = NblastArena::new(score_fn, false);
+        for offset in 0..5 {
+            let neuron = RStarPointTangents::new(
+                make_points(&[offset as Precision * 0.6, 0., 0.], &[0., 1., 0.], 10),
+                N_NEIGHBORS,
+            )
+            .expect("Construction failed");
+            arena.add_neuron(neuron);
+        }
+        let all = arena.all_v_all(true, &None);
+
+        let top = arena.top_k(2, 2, true, &None, None).expect("should exist");
+        assert_eq!(top.len(), 2);
+        let mut expected: Vec<_> = (0..5)
+            .filter(|idx| *idx != 2)
+            .map(|idx| (idx, all[&(2, idx)]))
+            .collect();
+        expected.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap().then(a.0.cmp(&b.0)));
+        assert_eq!(top, expected[..2].to_vec());
+
+        let cutoff = arena
+            .top_k(0, 10, true, &None, Some(all[&(0, 1)]))
+            .expect("should exist");
+        assert!(cutoff.iter().all(|(_, s)| *s >= all[&(0, 1)]));
+        assert!(cutoff.iter().any(|(idx, _)| *idx == 1));
+
+        assert!(arena.top_k(10, 2, true, &None, None).is_none());
+        let batch = arena.queries_top_k(&[0, 2, 10], 2, true, &None, None);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[&2], top);
+    }
+
+    #[test]
+    fn single_precision() {
+        let smat = ScoreMatrix::new(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0])
+            .expect("Invalid table");
+        let mut arena64 = NblastArena::new(smat.clone().to_fn(), true);
+        let mut arena32 = NblastArena::new(smat.to_fn(), true);
+        for offset in 0..4 {
+            let points = make_points(&[offset as Precision * 0.6, 0., 0.], &[0., 1., 0.], 10);
+            let points32: Vec<[f32; 3]> = points
+                .iter()
+                .map(|p| [p[0] as f32, p[1] as f32, p[2] as f32])
+                .collect();
+            arena64.add_neuron(
+                RStarPointTangents::new(points, N_NEIGHBORS).expect("Construction failed"),
+            );
+            arena32.add_neuron(
+                RStarPointTangents::new(points32, N_NEIGHBORS).expect("Construction failed"),
+            );
+        }
+        let all64 = arena64.all_v_all(true, &Some(Symmetry::GeometricMean));
+        let all32 = arena32.all_v_all(true, &Some(Symmetry::GeometricMean));
+        for (key, score) in all64.iter() {
+            assert_close(*score, Precision::from(all32[key]));
+        }
+    }
+
+    #[test]
+    fn max_dist() {
+        let target = KdTreePointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+            .expect("Construction failed");
+        let tangent = Unit::new_normalize(Vector3::new(1., 0., 0.));
+        let near = target.nearest_match_dist_dot(&[0., 3., 0.], &tangent, None, Some(5.0));
+        assert_close(near.dist, 3.0);
+        // outside the bounding box
+        assert_eq!(
+            target.nearest_match_dist_dot(&[0., 6., 0.], &tangent, None, Some(5.0)),
+            DistDot::out_of_range()
+        );
+        // inside the bounding box
+        assert_eq!(
+            target.nearest_match_dist_dot(&[4.5, 0., 0.], &tangent, None, Some(0.1)),
+            DistDot::out_of_range()
+        );
+
+        // scores beyond the outermost bin's lower bound are constant,
+        // or depend on the dot product so that there is no cutoff
+        for (outermost, expected_max_dist) in [
+            ([-1.0, -1.0], Some(2.0)),
+            ([-1.0, 0.5], None),
+        ]
+        .iter()
+        {
+            let mut cells = vec![8.0, 4.0, 2.0, 1.0];
+            cells.extend(outermost.iter());
+            let smat = ScoreMatrix::new(vec![1.0, 2.0, 10.0], vec![0.5, 1.0], cells)
+                .expect("Invalid table");
+            let mut exact = NblastArena::new(smat.clone().to_fn(), false);
+            let mut cutoff = NblastArena::from_score_matrix(smat, false);
+            assert_eq!(cutoff.max_dist(), *expected_max_dist);
+            for offset in [0., 0.5, 3.0, 20.0].iter() {
+                let neuron = RStarPointTangents::new(make_points(&[0., *offset, 0.], &[1., 0.1, 0.], 10), N_NEIGHBORS)
+                    .expect("Construction failed");
+                exact.add_neuron(neuron.clone());
+                cutoff.add_neuron(neuron);
+            }
+            let expected = exact.all_v_all(true, &Some(Symmetry::ArithmeticMean));
+            for (key, score) in cutoff.all_v_all(true, &Some(Symmetry::ArithmeticMean)).iter() {
+                assert_close(*score, expected[key]);
+            }
+        }
+
+        // with a single distance bin, every point match is in range
+        let single = ScoreMatrix::new(vec![10.0], vec![0.5, 1.0], vec![10.0, 20.0])
+            .expect("Invalid table");
+        let mut arena = NblastArena::from_score_matrix(single, false);
+        assert_eq!(arena.max_dist(), None);
+        let neuron = RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+            .expect("Construction failed");
+        let idx = arena.add_neuron(neuron.clone());
+        let other = arena.add_neuron(neuron);
+        assert_close(arena.query_target(idx, other, false, &None).expect("should exist"), 200.0);
+        let single = ScoreMatrix::new(vec![10.0], vec![0.5, 1.0], vec![10.0, 20.0])
+            .expect("Invalid table");
+        let arena: NblastArena<RStarPointTangents, _, _> = NblastArena::from_score_matrix_with_cutoff(single, false);
+        assert_eq!(arena.max_dist(), None);
+    }
+
+    #[test]
+    fn max_dist_opt_in() {
+        // the outermost row depends on the dot product, so only the opt-in constructor cuts off
+        let smat = ScoreMatrix::new(vec![1.0, 2.0, 10.0], vec![0.5, 1.0], vec![8.0, 4.0, 2.0, 1.0, -1.0, 0.5])
+            .expect("Invalid table");
+        let mut exact = NblastArena::from_score_matrix(smat.clone(), false);
+        let mut cutoff = NblastArena::from_score_matrix_with_cutoff(smat, false);
+        assert_eq!(exact.max_dist(), None);
+        assert_eq!(cutoff.max_dist(), Some(2.0));
+        for offset in [0., 20.0].iter() {
+            let neuron = RStarPointTangents::new(make_points(&[0., *offset, 0.], &[1., 0., 0.], 10), N_NEIGHBORS)
+                .expect("Construction failed");
+            exact.add_neuron(neuron.clone());
+            cutoff.add_neuron(neuron);
+        }
+        // parallel distant matches score by their dot product without the cutoff,
+        // and as out of range (a dot product of 0) with it
+        assert_close(exact.query_target(0, 1, false, &None).expect("should exist"), 5.0);
+        assert_close(cutoff.query_target(0, 1, false, &None).expect("should exist"), -10.0);
+        // self-hits are unchanged
+        assert_close(
+            cutoff.query_target(0, 0, false, &None).expect("should exist"),
+            exact.query_target(0, 0, false, &None).expect("should exist"),
+        );
+    }
+
+    fn all_v_all_with<I: SpatialIndex<Precision>>() -> HashMap<(NeuronIdx, NeuronIdx), Precision> {
+        let score_fn = table_to_fn(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0])
+            .expect("Invalid table");
+        let mut arena = NblastArena::new(score_fn, true);
+        for offset in 0..4 {
+            let points = make_points(&[offset as Precision * 0.6, 0., 0.5], &[0.2, 1., 0.], 20);
+            arena.add_neuron(
+                IndexedPointTangents::<Precision, I>::new(points, N_NEIGHBORS)
+                    .expect("Construction failed"),
+            );
+        }
+        arena.all_v_all(true, &None)
+    }
+
+    #[test]
+    fn distance_field_target() {
+        let points = make_points(&[0., 0., 0.], &[1., 0.5, 0.], 20);
+        let target = BruteForcePointTangents::new(points.clone(), N_NEIGHBORS)
+            .expect("Construction failed");
+        let query = QueryPointTangents::new(make_points(&[0.5, 2., 1.], &[1., 0.4, 0.], 20), N_NEIGHBORS)
+            .expect("Construction failed");
+        let score_fn = |dd: &DistDot| -dd.dist;
+        let expected = query.query(&target, false, &score_fn);
+
+        assert!(DistanceFieldPointTangents::from_target(target.clone(), 100).is_err());
+        let field = DistanceFieldPointTangents::from_target(target, 10_000)
+            .expect("Construction failed");
+        assert_eq!(field.points(), points);
+        assert_close(query.query(&field, false, &score_fn), expected);
+    }
+
+    #[test]
+    fn mirror_query() {
+        let points: Vec<_> = (0..20)
+            .map(|i| {
+                let i = i as Precision;
+                [5. + i, 0.1 * i * i, 2. * i.sin()]
+            })
+            .collect();
+        let left = RStarPointTangents::new(points, N_NEIGHBORS).expect("Construction failed");
+        let mirror = Transform::mirror(&[0., 0., 0.], &[1., 0., 0.]).expect("Invalid mirror");
+        let right: RStarPointTangents = mirror.transform_neuron(&left).expect("Transform failed");
+
+        let mut arena = NblastArena::new(|dd: &DistDot| dd.dot - dd.dist, false);
+        let l_idx = arena.add_neuron(left);
+        let r_idx = arena.add_neuron(right);
+
+        let sym = Some(Symmetry::ArithmeticMean);
+        let mirrored = arena
+            .query_target_mirrored(l_idx, r_idx, &mirror, true, &sym)
+            .expect("should exist");
+        assert_close(mirrored, 1.0);
+        let unmirrored = arena
+            .query_target(l_idx, r_idx, true, &sym)
+            .expect("should exist");
+        assert!(unmirrored < 0.0);
+        assert!(arena
+            .query_target_mirrored(l_idx, 2, &mirror, true, &None)
+            .is_none());
+    }
+
+    #[test]
+    fn aligned_query() {
+        let points: Vec<_> = (0..40)
+            .map(|i| {
+                let i = i as Precision * 0.3;
+                [5. * i.cos(), 5. * i.sin(), i]
+            })
+            .collect();
+        let target = RStarPointTangents::new(points, N_NEIGHBORS).expect("Construction failed");
+        let offset = Transform::new(Matrix3::identity(), Vector3::new(0.5, 0.2, -0.3))
+            .expect("Invalid transform");
+        let query: RStarPointTangents = offset.transform_neuron(&target).expect("Transform failed");
+
+        let mut arena = NblastArena::new(|dd: &DistDot| dd.dot - dd.dist, false);
+        let q_idx = arena.add_neuron(query);
+        let t_idx = arena.add_neuron(target);
+
+        let sym = Some(Symmetry::ArithmeticMean);
+        let unaligned = arena
+            .query_target(q_idx, t_idx, true, &sym)
+            .expect("should exist");
+        let (aligned, transform) = arena
+            .query_target_aligned(q_idx, t_idx, &Icp::default(), true, &sym)
+            .expect("should exist");
+        assert!(unaligned < 0.5);
+        assert!((aligned - 1.0).abs() < 1e-4);
+        let moved = transform.transform_point(&offset.transform_point(&[1., 2., 3.]));
+        assert!(moved.iter().zip([1., 2., 3.].iter()).all(|(a, b)| (a - b).abs() < 1e-4));
+    }
+
+    #[test]
+    fn spatial_indices() {
+        let expected = all_v_all_with::<RStarIndex<Precision>>();
+        for scores in [
+            all_v_all_with::<KdTree<Precision>>(),
+            all_v_all_with::<GridHash<Precision>>(),
+            all_v_all_with::<BruteForce<Precision>>(),
+            all_v_all_with::<DistanceField<Precision>>(),
+        ]
+        .iter()
+        {
+            for (key, score) in expected.iter() {
+                assert_close(*score, scores[key]);
+            }
+        }
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn arena_parallel() {
+        let score_fn = table_to_fn(vec![1.0, 2.0], vec![0.5, 1.0], vec![1.0, 2.0, 4.0, 8.0]).expect("Invalid table");
+        let mut arena = NblastArena::new(score_fn, false);
+        for offset in 0..5 {
+            let neuron = RStarPointTangents::new(
+                make_points(&[offset as Precision * 0.3, 0., 0.], &[1., 0.1, 0.], 10),
+                N_NEIGHBORS,
+            )
+            .expect("Construction failed");
+            arena.add_neuron(neuron);
+        }
+        arena
+            .set_threads(Some(2))
+            .expect("Could not build thread pool");
+
+        for symmetry in [None, Some(Symmetry::ArithmeticMean)].iter() {
+            let serial = arena.all_v_all(true, symmetry);
+            let parallel = arena.all_v_all_par(true, symmetry);
+            assert_eq!(serial, parallel);
+        }
+        assert_eq!(
+            arena.queries_top_k(&[0, 1, 2], 2, true, &None, None),
+            arena.queries_top_k_par(&[0, 1, 2], 2, true, &None, None),
+        );
+    }
+
     fn test_symmetry(symmetry: &Symmetry, a: Precision, b: Precision) {
         assert_close(
             apply_symmetry(symmetry, a, b),
@@ -0,0 +1,217 @@
+//! Analytic point match score functions, for when no trained score matrix is available.
+use serde::{Deserialize, Serialize};
+
+use std::convert::TryFrom;
+
+use crate::{DistDot, Error, Float, Precision};
+
+/// Factor of a [ParametricScore](struct.ParametricScore.html) which depends on distance.
+/// All kernels are 1 at zero distance.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum DistKernel {
+    /// `exp(-dist² / (2 * sigma²))`
+    Gaussian { sigma: Precision },
+    /// `1 - dist / cutoff`, and 0 beyond the cutoff.
+    Linear { cutoff: Precision },
+    /// 1 up to and including the cutoff, and 0 beyond it.
+    Step { cutoff: Precision },
+}
+
+impl DistKernel {
+    /// Check that the kernel's width is positive and finite,
+    /// so that it is defined at every distance.
+    fn validate(&self) -> Result<(), Error> {
+        let (width, msg) = match self {
+            DistKernel::Gaussian { sigma } => (sigma, "Gaussian sigma must be positive and finite"),
+            DistKernel::Linear { cutoff } | DistKernel::Step { cutoff } => {
+                (cutoff, "Kernel cutoff must be positive and finite")
+            }
+        };
+        if *width > 0.0 && width.is_finite() {
+            Ok(())
+        } else {
+            Err(Error::InvalidParameter(msg))
+        }
+    }
+
+    pub fn apply(&self, dist: Precision) -> Precision {
+        match self {
+            DistKernel::Gaussian { sigma } => (-dist * dist / (2.0 * sigma * sigma)).exp(),
+            DistKernel::Linear { cutoff } => (1.0 - dist / cutoff).max(0.0),
+            DistKernel::Step { cutoff } => {
+                if dist <= *cutoff {
+                    1.0
+                } else {
+                    0.0
+                }
+            }
+        }
+    }
+}
+
+/// Point match score of the form `kernel(dist) * dot^dot_power`.
+///
+/// The original NBLAST publication describes a Gaussian kernel with `dot_power` of 1,
+/// available as [ParametricScore::gaussian](#method.gaussian).
+/// A `dot_power` of 0 ignores the tangents.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawParametricScore")]
+pub struct ParametricScore {
+    kernel: DistKernel,
+    dot_power: Precision,
+}
+
+/// Unvalidated [ParametricScore](struct.ParametricScore.html), for deserialization.
+#[derive(Deserialize)]
+struct RawParametricScore {
+    kernel: DistKernel,
+    dot_power: Precision,
+}
+
+impl TryFrom<RawParametricScore> for ParametricScore {
+    type Error = Error;
+
+    fn try_from(raw: RawParametricScore) -> Result<Self, Self::Error> {
+        Self::new(raw.kernel, raw.dot_power)
+    }
+}
+
+impl ParametricScore {
+    /// Fails if the kernel's sigma or cutoff is not positive and finite,
+    /// or `dot_power` is negative or not finite.
+    pub fn new(kernel: DistKernel, dot_power: Precision) -> Result<Self, Error> {
+        kernel.validate()?;
+        if dot_power < 0.0 || !dot_power.is_finite() {
+            return Err(Error::InvalidParameter(
+                "Dot product power must be non-negative and finite",
+            ));
+        }
+        Ok(Self { kernel, dot_power })
+    }
+
+    /// `exp(-dist² / (2 * sigma²)) * dot`
+    pub fn gaussian(sigma: Precision) -> Result<Self, Error> {
+        Self::new(DistKernel::Gaussian { sigma }, 1.0)
+    }
+
+    pub fn kernel(&self) -> &DistKernel {
+        &self.kernel
+    }
+
+    pub fn dot_power(&self) -> Precision {
+        self.dot_power
+    }
+
+    /// The highest score of any point match (1).
+    pub fn max_score(&self) -> Precision {
+        1.0
+    }
+
+    /// Score a single point match.
+    pub fn score(&self, dd: &DistDot) -> Precision {
+        self.kernel.apply(dd.dist) * dd.dot.powf(self.dot_power)
+    }
+
+    /// Convert into a function which can be passed to neuron queries
+    /// and an [NblastArena](struct.NblastArena.html) of any precision.
+    pub fn to_fn<T: Float>(self) -> impl Fn(&DistDot<T>) -> T {
+        move |dd: &DistDot<T>| {
+            T::from_precision(self.score(&DistDot {
+                dist: dd.dist.to_precision(),
+                dot: dd.dot.to_precision(),
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EPSILON: Precision = 0.001;
+
+    fn assert_close(val1: Precision, val2: Precision) {
+        if (val1 - val2).abs() >= EPSILON {
+            panic!("Not close:\n\t{:?}\n\t{:?}", val1, val2);
+        }
+    }
+
+    #[test]
+    fn gaussian() {
+        let score_fn = ParametricScore::gaussian(2.0)
+            .expect("Invalid parameters")
+            .to_fn();
+        assert_close(score_fn(&DistDot::default()), 1.0);
+        assert_close(
+            score_fn(&DistDot {
+                dist: 2.0,
+                dot: 1.0,
+            }),
+            (-0.5 as Precision).exp(),
+        );
+        assert_close(
+            score_fn(&DistDot {
+                dist: 0.0,
+                dot: 0.5,
+            }),
+            0.5,
+        );
+    }
+
+    #[test]
+    fn kernels() {
+        let linear = DistKernel::Linear { cutoff: 4.0 };
+        assert_close(linear.apply(1.0), 0.75);
+        assert_close(linear.apply(5.0), 0.0);
+
+        let step = DistKernel::Step { cutoff: 4.0 };
+        assert_close(step.apply(4.0), 1.0);
+        assert_close(step.apply(4.1), 0.0);
+    }
+
+    #[test]
+    fn dot_power() {
+        let score = ParametricScore::new(DistKernel::Step { cutoff: 1.0 }, 2.0)
+            .expect("Invalid parameters");
+        assert_close(
+            score.score(&DistDot {
+                dist: 0.5,
+                dot: 0.5,
+            }),
+            0.25,
+        );
+        let no_dot = ParametricScore::new(DistKernel::Step { cutoff: 1.0 }, 0.0)
+            .expect("Invalid parameters");
+        assert_close(
+            no_dot.score(&DistDot {
+                dist: 0.5,
+                dot: 0.0,
+            }),
+            1.0,
+        );
+    }
+
+    #[test]
+    fn invalid_parameters() {
+        for kernel in [
+            DistKernel::Gaussian { sigma: 0.0 },
+            DistKernel::Linear { cutoff: 0.0 },
+            DistKernel::Step { cutoff: -1.0 },
+            DistKernel::Gaussian {
+                sigma: Precision::NAN,
+            },
+        ]
+        .iter()
+        {
+            match ParametricScore::new(*kernel, 1.0) {
+                Err(Error::InvalidParameter(_)) => (),
+                _ => panic!("Expected InvalidParameter for {:?}", kernel),
+            }
+        }
+        match ParametricScore::new(DistKernel::Linear { cutoff: 1.0 }, -1.0) {
+            Err(Error::InvalidParameter(_)) => (),
+            _ => panic!("Expected InvalidParameter"),
+        }
+        assert!(ParametricScore::gaussian(Precision::INFINITY).is_err());
+    }
+}
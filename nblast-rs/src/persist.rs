@@ -0,0 +1,473 @@
+//! Saving an arena to a binary file, and loading it without recalculating
+//! tangents or self-hit scores.
+use std::collections::HashMap;
+use std::io::{Read, Write};
+
+use nalgebra::base::{Unit, Vector3};
+use serde::{Deserialize, Serialize};
+
+use crate::spatial::SpatialIndex;
+use crate::{
+    BoxedScoreFn, DistDot, Error, Float, IndexedPointTangents, NblastArena, NeuronInfo,
+    ParametricScore, Precision, ScoreMatrix, TargetNeuron,
+};
+
+/// Identifies arena files.
+const ARENA_MAGIC: [u8; 8] = *b"NBLASTAR";
+/// Incremented whenever the layout of arena files changes.
+const ARENA_VERSION: u32 = 1;
+
+/// Description of the score function an arena was built with, stored in arena files
+/// so that loaded arenas score consistently with their saved self-hit scores.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum ScoreFnRecord {
+    /// Score table, as used by
+    /// [NblastArena::from_score_matrix](struct.NblastArena.html#method.from_score_matrix).
+    Table(ScoreMatrix),
+    /// Score table with interpolation between bins,
+    /// as from [ScoreMatrix::to_interp_fn](struct.ScoreMatrix.html#method.to_interp_fn).
+    InterpolatedTable(ScoreMatrix),
+    Parametric(ParametricScore),
+    /// Any other function, identified by a description;
+    /// the function itself must be given when loading.
+    /// Arenas which were not built from a recorded score function are saved with this.
+    Custom(String),
+}
+
+impl ScoreFnRecord {
+    /// Recreate the recorded score function.
+    /// Fails for [Custom](#variant.Custom) functions.
+    pub fn to_fn<T: Float>(&self) -> Result<BoxedScoreFn<T>, Error> {
+        match self {
+            ScoreFnRecord::Table(smat) => Ok(Box::new(smat.clone().to_fn())),
+            ScoreFnRecord::InterpolatedTable(smat) => Ok(Box::new(smat.clone().to_interp_fn())),
+            ScoreFnRecord::Parametric(p) => Ok(Box::new(p.to_fn())),
+            ScoreFnRecord::Custom(_) => Err(Error::InvalidParameter(
+                "Custom score functions cannot be recreated from an arena file",
+            )),
+        }
+    }
+
+    /// The highest score of any point match, unless the function is [Custom](#variant.Custom).
+    pub fn max_score(&self) -> Option<Precision> {
+        match self {
+            ScoreFnRecord::Table(smat) | ScoreFnRecord::InterpolatedTable(smat) => {
+                Some(smat.max_score())
+            }
+            ScoreFnRecord::Parametric(p) => Some(p.max_score()),
+            ScoreFnRecord::Custom(_) => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+}
+
+/// Everything needed to recreate a neuron, its self-hit score and its labels.
+#[derive(Serialize, Deserialize)]
+struct NeuronRecord {
+    points: Vec<[Precision; 3]>,
+    tangents: Vec<[Precision; 3]>,
+    alphas: Vec<Precision>,
+    self_hit: Precision,
+    name: Option<String>,
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArenaRecord {
+    score_fn: ScoreFnRecord,
+    use_alpha: bool,
+    max_dist: Option<Precision>,
+    /// Removed neurons are `None`, so that indices are unchanged.
+    neurons: Vec<Option<NeuronRecord>>,
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        match *e {
+            bincode::ErrorKind::Io(io_err) => Error::Io(io_err),
+            kind => Error::Parse(kind.to_string()),
+        }
+    }
+}
+
+fn to_precision<T: Float>(point: &[T; 3]) -> [Precision; 3] {
+    [
+        point[0].to_precision(),
+        point[1].to_precision(),
+        point[2].to_precision(),
+    ]
+}
+
+fn from_precision<T: Float>(point: &[Precision; 3]) -> [T; 3] {
+    [
+        T::from_precision(point[0]),
+        T::from_precision(point[1]),
+        T::from_precision(point[2]),
+    ]
+}
+
+/// A saved tangent, renormalised if it is not unit-length to within rounding,
+/// or `None` if it is zero or not finite.
+/// Unit tangents are kept exactly, so that saved arenas load unchanged.
+fn to_tangent<T: Float>(tangent: &[Precision; 3]) -> Option<Unit<Vector3<T>>> {
+    let vector = Vector3::from(from_precision::<T>(tangent));
+    let norm = vector.norm();
+    if !norm.is_finite() || norm <= T::zero() {
+        None
+    } else if (norm - T::one()).abs() <= T::default_epsilon().sqrt() {
+        Some(Unit::new_unchecked(vector))
+    } else {
+        Some(Unit::new_unchecked(vector / norm))
+    }
+}
+
+impl<N, F, T> NblastArena<N, F, T>
+where
+    N: TargetNeuron<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    /// Write the arena's neurons, self-hit scores, names, metadata and settings
+    /// to a versioned binary file, which can be read by [load](#method.load).
+    /// Indices are preserved.
+    ///
+    /// The score function cannot be saved itself, only its [record](#method.score_fn_record):
+    /// arenas created with [new](#method.new) record a [custom](enum.ScoreFnRecord.html#variant.Custom)
+    /// function, which must be given again when loading,
+    /// unless another record is [set](#method.set_score_fn_record).
+    pub fn save<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let neurons = self
+            .neurons_scores
+            .iter()
+            .enumerate()
+            .map(|(idx, ns)| {
+                ns.as_ref().map(|(neuron, self_hit)| {
+                    let NeuronInfo { name, metadata } =
+                        self.info.get(&idx).cloned().unwrap_or_default();
+                    NeuronRecord {
+                        points: neuron.points().iter().map(to_precision).collect(),
+                        tangents: neuron
+                            .tangents()
+                            .iter()
+                            .map(|t| to_precision(&[t[0], t[1], t[2]]))
+                            .collect(),
+                        alphas: neuron.alphas().iter().map(|a| a.to_precision()).collect(),
+                        self_hit: self_hit.to_precision(),
+                        name,
+                        metadata,
+                    }
+                })
+            })
+            .collect();
+        let record = ArenaRecord {
+            score_fn: self
+                .score_fn_record
+                .clone()
+                .unwrap_or_else(|| ScoreFnRecord::Custom("Unrecorded".to_owned())),
+            use_alpha: self.use_alpha,
+            max_dist: self.max_dist.map(|d| d.to_precision()),
+            neurons,
+        };
+        let mut writer = writer;
+        let header = Header {
+            magic: ARENA_MAGIC,
+            version: ARENA_VERSION,
+        };
+        bincode::serialize_into(&mut writer, &header)?;
+        bincode::serialize_into(&mut writer, &record)?;
+        Ok(())
+    }
+}
+
+impl<I, F, T> NblastArena<IndexedPointTangents<T, I>, F, T>
+where
+    I: SpatialIndex<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    /// Read an arena written by [save](#method.save),
+    /// building each neuron's spatial index from its saved points
+    /// but not recalculating its tangents or self-hit score.
+    /// Tangents are renormalised, and fail to load if they are zero or not finite.
+    ///
+    /// `score_fn` should be the function described by the returned record:
+    /// see [load_recorded](#method.load_recorded) to recreate it automatically.
+    pub fn load<R: Read>(reader: R, score_fn: F) -> Result<(Self, ScoreFnRecord), Error> {
+        let mut reader = reader;
+        let header: Header = bincode::deserialize_from(&mut reader)?;
+        if header.magic != ARENA_MAGIC {
+            return Err(Error::Parse("Not an arena file".to_owned()));
+        }
+        if header.version != ARENA_VERSION {
+            return Err(Error::Parse(format!(
+                "Unsupported arena file version {}",
+                header.version
+            )));
+        }
+        let record: ArenaRecord = bincode::deserialize_from(&mut reader)?;
+
+        let mut arena = Self::new(score_fn, record.use_alpha);
+        arena.score_fn_record = Some(record.score_fn.clone());
+        arena.max_dist = record.max_dist.map(T::from_precision);
+        for (idx, neuron) in record.neurons.into_iter().enumerate() {
+            let neuron = match neuron {
+                Some(n) => n,
+                None => {
+                    arena.neurons_scores.push(None);
+                    continue;
+                }
+            };
+            let target = IndexedPointTangents::new_with_tangents_alphas(
+                neuron.points.iter().map(from_precision).collect(),
+                neuron
+                    .tangents
+                    .iter()
+                    .enumerate()
+                    .map(|(point_idx, t)| {
+                        to_tangent(t).ok_or_else(|| {
+                            Error::Parse(format!(
+                                "Neuron {} has an invalid tangent at index {}",
+                                idx, point_idx
+                            ))
+                        })
+                    })
+                    .collect::<Result<_, _>>()?,
+                neuron.alphas.into_iter().map(T::from_precision).collect(),
+            )?;
+            arena
+                .neurons_scores
+                .push(Some((target, T::from_precision(neuron.self_hit))));
+            arena.n_neurons += 1;
+            if let Some(name) = &neuron.name {
+                if arena.names.insert(name.clone(), idx).is_some() {
+                    return Err(Error::DuplicateName(name.clone()));
+                }
+            }
+            arena.info.insert(
+                idx,
+                NeuronInfo {
+                    name: neuron.name,
+                    metadata: neuron.metadata,
+                },
+            );
+        }
+        Ok((arena, record.score_fn))
+    }
+}
+
+impl<I, T> NblastArena<IndexedPointTangents<T, I>, BoxedScoreFn<T>, T>
+where
+    I: SpatialIndex<T>,
+    T: Float,
+{
+    /// As [load](#method.load), recreating the score function from the file's record.
+    /// Fails for [custom](enum.ScoreFnRecord.html#variant.Custom) score functions.
+    pub fn load_recorded<R: Read>(reader: R) -> Result<Self, Error> {
+        // placeholder until the record has been read
+        let (arena, record) = Self::load(
+            reader,
+            Box::new(|_: &DistDot<T>| T::zero()) as BoxedScoreFn<T>,
+        )?;
+        Ok(Self {
+            score_fn: record.to_fn()?,
+            ..arena
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{KdTreePointTangents, RStarPointTangents, Symmetry};
+
+    fn populate(
+        mut arena: NblastArena<RStarPointTangents, BoxedScoreFn>,
+    ) -> NblastArena<RStarPointTangents, BoxedScoreFn> {
+        for i in 0..4 {
+            let points: Vec<_> = (0..15)
+                .map(|j| {
+                    let j = j as Precision;
+                    [
+                        j,
+                        (j * 0.5 + i as Precision).sin(),
+                        0.3 * j * i as Precision,
+                    ]
+                })
+                .collect();
+            let neuron = RStarPointTangents::new(points, 5).expect("Construction failed");
+            if i % 2 == 0 {
+                let idx = arena
+                    .add_named_neuron(&format!("n{}", i), neuron)
+                    .expect("Duplicate name");
+                arena
+                    .set_metadata(idx, "cell_type", "KC")
+                    .expect("Neuron should exist");
+            } else {
+                arena.add_neuron(neuron);
+            }
+        }
+        arena.remove_neuron(1);
+        arena.set_max_dist(Some(4.0));
+        arena
+    }
+
+    #[test]
+    fn roundtrip() {
+        let score = ParametricScore::gaussian(2.0).expect("Invalid parameters");
+        let record = ScoreFnRecord::Parametric(score);
+        let arena = populate(NblastArena::from_parametric(score, true));
+        assert_eq!(arena.score_fn_record(), Some(&record));
+        let mut buf = Vec::default();
+        arena.save(&mut buf).expect("Could not save arena");
+
+        let loaded: NblastArena<RStarPointTangents, _> =
+            NblastArena::load_recorded(buf.as_slice()).expect("Could not load arena");
+        assert_eq!(loaded.ids().collect::<Vec<_>>(), vec![0, 2, 3]);
+        assert_eq!(loaded.max_dist(), Some(4.0));
+        assert!(loaded.use_alpha());
+        assert_eq!(loaded.score_fn_record(), Some(&record));
+        assert_eq!(loaded.idx_by_name("n2"), Some(2));
+        assert_eq!(loaded.metadata(0), arena.metadata(0));
+        for idx in arena.ids() {
+            assert_eq!(loaded.points(idx), arena.points(idx));
+            assert_eq!(loaded.tangents(idx), arena.tangents(idx));
+            assert_eq!(loaded.alphas(idx), arena.alphas(idx));
+            assert_eq!(loaded.self_hit(idx), arena.self_hit(idx));
+        }
+        let sym = Some(Symmetry::GeometricMean);
+        assert_eq!(loaded.all_v_all(true, &sym), arena.all_v_all(true, &sym));
+
+        // any index, and any precision
+        let (single, loaded_record) = NblastArena::<KdTreePointTangents<f32>, _, f32>::load(
+            buf.as_slice(),
+            |dd: &DistDot<f32>| dd.dot,
+        )
+        .expect("Could not load arena");
+        assert_eq!(loaded_record, record);
+        assert_eq!(single.len(), 3);
+    }
+
+    #[test]
+    fn roundtrip_interpolated() {
+        let smat = ScoreMatrix::new(
+            vec![1.0, 2.0, 10.0],
+            vec![0.5, 1.0],
+            vec![8.0, 4.0, 2.0, 1.0, -1.0, 0.5],
+        )
+        .expect("Invalid table");
+        let record = ScoreFnRecord::InterpolatedTable(smat.clone());
+        let arena = populate(NblastArena::from_interp_score_matrix(smat, false));
+        assert_eq!(arena.score_fn_record(), Some(&record));
+        let mut buf = Vec::default();
+        arena.save(&mut buf).expect("Could not save arena");
+
+        let loaded: NblastArena<RStarPointTangents, _> =
+            NblastArena::load_recorded(buf.as_slice()).expect("Could not load arena");
+        assert_eq!(loaded.score_fn_record(), Some(&record));
+        let sym = Some(Symmetry::ArithmeticMean);
+        assert_eq!(loaded.all_v_all(true, &sym), arena.all_v_all(true, &sym));
+    }
+
+    #[test]
+    fn set_record() {
+        let mut arena = populate(NblastArena::new(Box::new(|dd: &DistDot| dd.dot), true));
+        let record = ScoreFnRecord::Custom("dot product".to_owned());
+        arena.set_score_fn_record(Some(record.clone()));
+        assert_eq!(arena.score_fn_record(), Some(&record));
+        let mut buf = Vec::default();
+        arena.save(&mut buf).expect("Could not save arena");
+
+        let (loaded, loaded_record) =
+            NblastArena::<RStarPointTangents, _>::load(buf.as_slice(), |dd: &DistDot| dd.dot)
+                .expect("Could not load arena");
+        assert_eq!(loaded_record, record);
+        assert_eq!(loaded.score_fn_record(), Some(&record));
+        assert_eq!(loaded.all_v_all(true, &None), arena.all_v_all(true, &None));
+
+        arena.set_score_fn_record(None);
+        assert!(arena.score_fn_record().is_none());
+    }
+
+    #[test]
+    fn invalid_files() {
+        let arena = populate(NblastArena::new(Box::new(|dd: &DistDot| dd.dot), true));
+        assert!(arena.score_fn_record().is_none());
+        let mut buf = Vec::default();
+        arena.save(&mut buf).expect("Could not save arena");
+        match NblastArena::<RStarPointTangents, BoxedScoreFn>::load_recorded(buf.as_slice()) {
+            Err(Error::InvalidParameter(_)) => (),
+            _ => panic!("Expected InvalidParameter error"),
+        }
+
+        let mut wrong_version = buf.clone();
+        wrong_version[ARENA_MAGIC.len()] += 1;
+        match NblastArena::<RStarPointTangents, _>::load(
+            wrong_version.as_slice(),
+            |dd: &DistDot| dd.dot,
+        ) {
+            Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Parse error"),
+        }
+        match NblastArena::<RStarPointTangents, _>::load(&buf[..buf.len() / 2], |dd: &DistDot| {
+            dd.dot
+        }) {
+            Err(Error::Io(_)) | Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Io or Parse error"),
+        }
+        match NblastArena::<RStarPointTangents, _>::load(
+            &b"not an arena file"[..],
+            |dd: &DistDot| dd.dot,
+        ) {
+            Err(Error::Parse(_)) => (),
+            _ => panic!("Expected Parse error"),
+        }
+    }
+
+    fn save_tangent(tangent: [Precision; 3]) -> Vec<u8> {
+        let record = ArenaRecord {
+            score_fn: ScoreFnRecord::Custom("dot product".to_owned()),
+            use_alpha: false,
+            max_dist: None,
+            neurons: vec![Some(NeuronRecord {
+                points: vec![[0.0, 0.0, 0.0], [1.0, 0.0, 0.0]],
+                tangents: vec![[1.0, 0.0, 0.0], tangent],
+                alphas: vec![1.0, 1.0],
+                self_hit: 2.0,
+                name: None,
+                metadata: HashMap::default(),
+            })],
+        };
+        let mut buf = Vec::default();
+        let header = Header {
+            magic: ARENA_MAGIC,
+            version: ARENA_VERSION,
+        };
+        bincode::serialize_into(&mut buf, &header).expect("Could not write header");
+        bincode::serialize_into(&mut buf, &record).expect("Could not write record");
+        buf
+    }
+
+    #[test]
+    fn renormalise_tangents() {
+        let buf = save_tangent([0.0, 3.0, 4.0]);
+        let (arena, _) =
+            NblastArena::<RStarPointTangents, _>::load(buf.as_slice(), |dd: &DistDot| dd.dot)
+                .expect("Could not load arena");
+        let tangents = arena.tangents(0).expect("Neuron should exist");
+        assert!((tangents[1].into_inner() - Vector3::new(0.0, 0.6, 0.8)).norm() < 1e-12);
+
+        for tangent in [[0.0; 3], [Precision::NAN, 0.0, 0.0], [1e300; 3]].iter() {
+            let buf = save_tangent(*tangent);
+            match NblastArena::<RStarPointTangents, _>::load(buf.as_slice(), |dd: &DistDot| dd.dot)
+            {
+                Err(Error::Parse(_)) => (),
+                _ => panic!("Expected Parse error"),
+            }
+        }
+    }
+}
@@ -0,0 +1,629 @@
+//! Score matrices: reading, writing, and training them from neurons known to be related or unrelated.
+use std::collections::HashSet;
+use std::convert::TryFrom;
+use std::io::{Read, Write};
+
+use csv::{ReaderBuilder, WriterBuilder};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    table_idx, table_interp_lookup, table_lookup, DistDot, Error, Float, NblastArena,
+    NeuronIdx, Precision, QueryNeuron, TargetNeuron,
+};
+
+/// Added to the probability of each bin so that empty bins have finite log odds.
+/// Matches the value used by NAT's `create_scoringmatrix`.
+const EPSILON: Precision = 1e-6;
+
+/// Distance thresholds, dot product thresholds, and dist-major cells.
+type Table = (Vec<Precision>, Vec<Precision>, Vec<Precision>);
+
+/// Parse the upper bound of a bin from a CSV header,
+/// e.g. `"(0.75,1.5]"` or `"1.5"`.
+/// All brackets and the lower bound are ignored.
+fn parse_interval(s: &str) -> Result<Precision, Error> {
+    let no_brackets = s.trim_matches(|c: char| "([]) ".contains(c));
+    no_brackets
+        .rsplit(',')
+        .next()
+        .and_then(|upper| upper.trim().parse::<Precision>().ok())
+        .ok_or_else(|| Error::MalformedTable(format!("Could not parse bin interval {:?}", s)))
+}
+
+/// A lookup table mapping point match (distance, absolute dot product) bins to scores,
+/// as used by NBLAST.
+///
+/// Each bin is identified by its upper bound:
+/// the lower bound is implicitly the previous bin's upper bound, or zero.
+/// Values outside of the range fall into the bottom or top bin.
+/// Cells are stored in dist-major order,
+/// i.e. distance bins on the rows and dot product bins on the columns.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "RawScoreMatrix")]
+pub struct ScoreMatrix {
+    dist_thresholds: Vec<Precision>,
+    dot_thresholds: Vec<Precision>,
+    cells: Vec<Precision>,
+}
+
+/// Unvalidated [ScoreMatrix](struct.ScoreMatrix.html), for deserialization.
+#[derive(Deserialize)]
+struct RawScoreMatrix {
+    dist_thresholds: Vec<Precision>,
+    dot_thresholds: Vec<Precision>,
+    cells: Vec<Precision>,
+}
+
+impl TryFrom<RawScoreMatrix> for ScoreMatrix {
+    type Error = Error;
+
+    fn try_from(raw: RawScoreMatrix) -> Result<Self, Self::Error> {
+        Self::new(raw.dist_thresholds, raw.dot_thresholds, raw.cells)
+    }
+}
+
+fn is_increasing(thresholds: &[Precision]) -> bool {
+    thresholds.windows(2).all(|w| w[0] < w[1])
+}
+
+fn check_thresholds(
+    dist_thresholds: &[Precision],
+    dot_thresholds: &[Precision],
+) -> Result<(), Error> {
+    if dist_thresholds.is_empty() || dot_thresholds.is_empty() {
+        return Err(Error::MalformedTable(
+            "At least one distance and dot product threshold required".to_owned(),
+        ));
+    }
+    if !is_increasing(dist_thresholds) || !is_increasing(dot_thresholds) {
+        return Err(Error::MalformedTable(
+            "Thresholds must be strictly increasing".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// (lower, upper) bounds of each bin.
+fn bins(thresholds: &[Precision]) -> Vec<(Precision, Precision)> {
+    let mut lower = 0.0;
+    thresholds
+        .iter()
+        .map(|upper| {
+            let bin = (lower, *upper);
+            lower = *upper;
+            bin
+        })
+        .collect()
+}
+
+impl ScoreMatrix {
+    /// Thresholds are the upper bounds of each bin, and must be strictly increasing.
+    /// Cells are in dist-major order.
+    pub fn new(
+        dist_thresholds: Vec<Precision>,
+        dot_thresholds: Vec<Precision>,
+        cells: Vec<Precision>,
+    ) -> Result<Self, Error> {
+        check_thresholds(&dist_thresholds, &dot_thresholds)?;
+        if dist_thresholds.len() * dot_thresholds.len() != cells.len() {
+            return Err(Error::MalformedTable(format!(
+                "{} cells do not match {} rows and {} columns",
+                cells.len(),
+                dist_thresholds.len(),
+                dot_thresholds.len()
+            )));
+        }
+        Ok(Self {
+            dist_thresholds,
+            dot_thresholds,
+            cells,
+        })
+    }
+
+    /// Read a score matrix from a CSV with distance bins on the row index
+    /// and dot product bins on the column index, as written by NAT.
+    ///
+    /// Row and column indices are strings describing the upper bound of the bin,
+    /// or the interval that bin covers, e.g. `"(0.75,1.5]"`.
+    /// N.B. all brackets are ignored; the upper value is assumed to be closed
+    /// and the lower value is assumed to be 0 or the upper value of the previous bin.
+    pub fn from_csv<R: Read>(reader: R) -> Result<Self, Error> {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(reader);
+        let mut records = reader.records();
+
+        let header = records
+            .next()
+            .ok_or_else(|| Error::MalformedTable("No header row".to_owned()))??;
+        // drop first (empty) column
+        let dot_thresholds = header
+            .iter()
+            .skip(1)
+            .map(parse_interval)
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut dist_thresholds = Vec::new();
+        let mut cells = Vec::new();
+        for result in records {
+            let record = result?;
+            if record.len() != dot_thresholds.len() + 1 {
+                return Err(Error::MalformedTable(format!(
+                    "Row of length {} does not match header of length {}",
+                    record.len(),
+                    dot_thresholds.len() + 1
+                )));
+            }
+            let mut record_iter = record.iter();
+            // length checked above, so there is always a row index
+            dist_thresholds.push(parse_interval(record_iter.next().unwrap_or_default())?);
+            for cell in record_iter {
+                cells.push(cell.trim().parse::<Precision>().map_err(|_| {
+                    Error::MalformedTable(format!("Cell {:?} is not a number", cell))
+                })?);
+            }
+        }
+        Self::new(dist_thresholds, dot_thresholds, cells)
+    }
+
+    /// Write the score matrix as a CSV in the same format as
+    /// [from_csv](#method.from_csv) reads, with intervals in the indices.
+    pub fn to_csv<W: Write>(&self, writer: W) -> Result<(), Error> {
+        let mut writer = WriterBuilder::new().from_writer(writer);
+        let fmt_bin = |(lower, upper): (Precision, Precision)| format!("({},{}]", lower, upper);
+
+        let mut header = vec![String::default()];
+        header.extend(self.dot_bins().into_iter().map(fmt_bin));
+        writer.write_record(&header)?;
+
+        for (dist_bin, row) in self
+            .dist_bins()
+            .into_iter()
+            .zip(self.cells.chunks(self.dot_thresholds.len()))
+        {
+            let mut record = vec![fmt_bin(dist_bin)];
+            record.extend(row.iter().map(|c| c.to_string()));
+            writer.write_record(&record)?;
+        }
+        writer.flush()?;
+        Ok(())
+    }
+
+    /// Upper bounds of the distance bins.
+    pub fn dist_thresholds(&self) -> &[Precision] {
+        &self.dist_thresholds
+    }
+
+    /// Upper bounds of the dot product bins.
+    pub fn dot_thresholds(&self) -> &[Precision] {
+        &self.dot_thresholds
+    }
+
+    /// Cells in dist-major order.
+    pub fn cells(&self) -> &[Precision] {
+        &self.cells
+    }
+
+    /// (lower, upper) bounds of each distance bin.
+    pub fn dist_bins(&self) -> Vec<(Precision, Precision)> {
+        bins(&self.dist_thresholds)
+    }
+
+    /// (lower, upper) bounds of each dot product bin.
+    pub fn dot_bins(&self) -> Vec<(Precision, Precision)> {
+        bins(&self.dot_thresholds)
+    }
+
+    /// Number of (distance, dot product) bins.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.dist_thresholds.len(), self.dot_thresholds.len())
+    }
+
+    /// The value of the cell at the given distance and dot product bin indices.
+    pub fn get(&self, dist_idx: usize, dot_idx: usize) -> Option<Precision> {
+        if dist_idx >= self.dist_thresholds.len() || dot_idx >= self.dot_thresholds.len() {
+            return None;
+        }
+        Some(self.cells[dist_idx * self.dot_thresholds.len() + dot_idx])
+    }
+
+    /// The highest score of any point match,
+    /// e.g. for [bounded queries](struct.NblastArena.html#method.query_target_bounded).
+    pub fn max_score(&self) -> Precision {
+        self.cells[1..]
+            .iter()
+            .fold(self.cells[0], |max, cell| max.max(*cell))
+    }
+
+    /// The lower bound of the outermost distance bin,
+    /// or `None` if there is only one distance bin.
+    pub fn outermost_dist(&self) -> Option<Precision> {
+        let n_dists = self.dist_thresholds.len();
+        if n_dists < 2 {
+            None
+        } else {
+            Some(self.dist_thresholds[n_dists - 2])
+        }
+    }
+
+    /// The lower bound of the outermost distance bin,
+    /// if every cell in that row has the same value:
+    /// all point matches further than this then have the same score,
+    /// whatever their distance and dot product.
+    /// `None` if the outermost row depends on the dot product,
+    /// or there is only one distance bin.
+    ///
+    /// Tables built from real neuron matches, like the FlyCircuit table,
+    /// score the outermost row by dot product and so never have this cutoff;
+    /// see [outermost_dist](#method.outermost_dist) for the lower bound regardless.
+    /// See [NblastArena::set_max_dist](struct.NblastArena.html#method.set_max_dist).
+    pub fn max_dist(&self) -> Option<Precision> {
+        let max_dist = self.outermost_dist()?;
+        let outermost = &self.cells[(self.dist_thresholds.len() - 1) * self.dot_thresholds.len()..];
+        if outermost.iter().all(|c| *c == outermost[0]) {
+            Some(max_dist)
+        } else {
+            None
+        }
+    }
+
+    /// Score a single point match.
+    pub fn score(&self, dd: &DistDot) -> Precision {
+        table_lookup(&self.dist_thresholds, &self.dot_thresholds, &self.cells, dd)
+    }
+
+    /// Score a single point match, bilinearly interpolating between bin centres.
+    /// See [table_to_interp_fn](fn.table_to_interp_fn.html).
+    pub fn interp_score(&self, dd: &DistDot) -> Precision {
+        table_interp_lookup(&self.dist_thresholds, &self.dot_thresholds, &self.cells, dd)
+    }
+
+    /// Thresholds and cells converted to the given precision.
+    fn converted<T: Float>(&self) -> (Vec<T>, Vec<T>, Vec<T>) {
+        let convert = |v: &[Precision]| v.iter().map(|x| T::from_precision(*x)).collect();
+        (
+            convert(&self.dist_thresholds),
+            convert(&self.dot_thresholds),
+            convert(&self.cells),
+        )
+    }
+
+    /// Convert into a function which can be passed to neuron queries
+    /// and an [NblastArena](struct.NblastArena.html) of any precision.
+    pub fn to_fn<T: Float>(self) -> impl Fn(&DistDot<T>) -> T {
+        let (dist_thresholds, dot_thresholds, cells) = self.converted();
+        move |dd: &DistDot<T>| table_lookup(&dist_thresholds, &dot_thresholds, &cells, dd)
+    }
+
+    /// Convert into an interpolating function which can be passed to neuron queries
+    /// and an [NblastArena](struct.NblastArena.html) of any precision.
+    pub fn to_interp_fn<T: Float>(self) -> impl Fn(&DistDot<T>) -> T {
+        let (dist_thresholds, dot_thresholds, cells) = self.converted();
+        move |dd: &DistDot<T>| {
+            table_interp_lookup(&dist_thresholds, &dot_thresholds, &cells, dd)
+        }
+    }
+
+    /// Distance thresholds, dot product thresholds, and cells,
+    /// i.e. the arguments to [table_to_fn](fn.table_to_fn.html).
+    pub fn into_parts(self) -> Table {
+        (self.dist_thresholds, self.dot_thresholds, self.cells)
+    }
+}
+
+/// Count the point matches of `query` against `target` falling into each
+/// (distance, dot product) bin, in dist-major order.
+fn count_dist_dots<T: Float>(
+    query: &impl QueryNeuron<T>,
+    target: &impl TargetNeuron<T>,
+    use_alpha: bool,
+    dist_thresholds: &[Precision],
+    dot_thresholds: &[Precision],
+    counts: &mut [usize],
+) -> usize {
+    let mut total = 0;
+    for ((point, tangent), alpha) in query
+        .points()
+        .iter()
+        .zip(query.tangents().iter())
+        .zip(query.alphas())
+    {
+        let alpha = if use_alpha { Some(alpha) } else { None };
+        let DistDot { dist, dot } = target.nearest_match_dist_dot(point, tangent, alpha, None);
+        let dd = DistDot {
+            dist: dist.to_precision(),
+            dot: dot.to_precision(),
+        };
+        counts[table_idx(dist_thresholds, dot_thresholds, &dd)] += 1;
+        total += 1;
+    }
+    total
+}
+
+/// Train a score matrix from the neurons in an [NblastArena](struct.NblastArena.html).
+///
+/// Neurons are added in groups.
+/// Every ordered pair of neurons within a matching group is treated as a true match;
+/// every ordered pair within a non-matching group
+/// (which is not also in a matching group) is treated as unrelated.
+///
+/// Each cell of the resulting table is the log2 ratio of the probability
+/// of a point match falling into that (distance, dot product) bin
+/// for matching pairs, to that for non-matching pairs.
+/// The bins are defined by their upper bounds, as in [table_to_fn](fn.table_to_fn.html).
+/// Point matches are weighted by alpha if the arena uses alpha.
+pub struct ScoreMatrixBuilder<'a, N, F, T = Precision>
+where
+    N: TargetNeuron<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    arena: &'a NblastArena<N, F, T>,
+    dist_thresholds: Vec<Precision>,
+    dot_thresholds: Vec<Precision>,
+    matching_sets: Vec<Vec<NeuronIdx>>,
+    nonmatching_sets: Vec<Vec<NeuronIdx>>,
+}
+
+impl<'a, N, F, T> ScoreMatrixBuilder<'a, N, F, T>
+where
+    N: TargetNeuron<T>,
+    F: Fn(&DistDot<T>) -> T,
+    T: Float,
+{
+    /// `dist_thresholds` and `dot_thresholds` are the upper bounds of each bin.
+    pub fn new(
+        arena: &'a NblastArena<N, F, T>,
+        dist_thresholds: Vec<Precision>,
+        dot_thresholds: Vec<Precision>,
+    ) -> Self {
+        Self {
+            arena,
+            dist_thresholds,
+            dot_thresholds,
+            matching_sets: Vec::default(),
+            nonmatching_sets: Vec::default(),
+        }
+    }
+
+    /// Add a group of neurons which are all related to each other,
+    /// e.g. of the same cell type.
+    pub fn add_matching_set(&mut self, idxs: &[NeuronIdx]) -> &mut Self {
+        self.matching_sets.push(idxs.to_vec());
+        self
+    }
+
+    /// Add a group of neurons which are not expected to be related to each other,
+    /// e.g. a random sample from the arena.
+    /// Pairs which are also in a matching set are ignored.
+    pub fn add_nonmatching_set(&mut self, idxs: &[NeuronIdx]) -> &mut Self {
+        self.nonmatching_sets.push(idxs.to_vec());
+        self
+    }
+
+    fn matching_pairs(&self) -> HashSet<(NeuronIdx, NeuronIdx)> {
+        let mut out = HashSet::default();
+        for set in self.matching_sets.iter() {
+            for q_idx in set.iter() {
+                for t_idx in set.iter() {
+                    if q_idx != t_idx {
+                        out.insert((*q_idx, *t_idx));
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn nonmatching_pairs(
+        &self,
+        matching: &HashSet<(NeuronIdx, NeuronIdx)>,
+    ) -> HashSet<(NeuronIdx, NeuronIdx)> {
+        let mut out = HashSet::default();
+        for set in self.nonmatching_sets.iter() {
+            for q_idx in set.iter() {
+                for t_idx in set.iter() {
+                    let key = (*q_idx, *t_idx);
+                    if q_idx != t_idx && !matching.contains(&key) {
+                        out.insert(key);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// Normalised frequency of point matches in each bin, for the given neuron pairs.
+    fn bin_probabilities(
+        &self,
+        pairs: &HashSet<(NeuronIdx, NeuronIdx)>,
+    ) -> Result<Vec<Precision>, Error> {
+        let mut counts = vec![0; self.dist_thresholds.len() * self.dot_thresholds.len()];
+        let mut total = 0;
+        for (q_idx, t_idx) in pairs.iter() {
+            let query = self
+                .arena
+                .neuron(*q_idx)
+                .ok_or(Error::MissingNeuron(*q_idx))?;
+            let target = self
+                .arena
+                .neuron(*t_idx)
+                .ok_or(Error::MissingNeuron(*t_idx))?;
+            total += count_dist_dots(
+                query,
+                target,
+                self.arena.use_alpha(),
+                &self.dist_thresholds,
+                &self.dot_thresholds,
+                &mut counts,
+            );
+        }
+        if total == 0 {
+            return Err(Error::InsufficientTrainingData(
+                "No point matches to train on",
+            ));
+        }
+        Ok(counts
+            .into_iter()
+            .map(|c| c as Precision / total as Precision)
+            .collect())
+    }
+
+    /// Calculate the score matrix.
+    pub fn build(&self) -> Result<ScoreMatrix, Error> {
+        check_thresholds(&self.dist_thresholds, &self.dot_thresholds)?;
+        let matching = self.matching_pairs();
+        if matching.is_empty() {
+            return Err(Error::InsufficientTrainingData("No matching pairs"));
+        }
+        let nonmatching = self.nonmatching_pairs(&matching);
+        if nonmatching.is_empty() {
+            return Err(Error::InsufficientTrainingData("No non-matching pairs"));
+        }
+
+        let match_probs = self.bin_probabilities(&matching)?;
+        let nonmatch_probs = self.bin_probabilities(&nonmatching)?;
+        let cells = match_probs
+            .into_iter()
+            .zip(nonmatch_probs)
+            .map(|(m, nm)| ((m + EPSILON) / (nm + EPSILON)).log2())
+            .collect();
+
+        ScoreMatrix::new(
+            self.dist_thresholds.clone(),
+            self.dot_thresholds.clone(),
+            cells,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::make_points;
+    use crate::{table_to_fn, RStarPointTangents};
+
+    #[test]
+    fn matching_scores_higher() {
+        let score_fn = table_to_fn(vec![1.0], vec![1.0], vec![1.0]).expect("Invalid table");
+        let mut arena = NblastArena::new(score_fn, false);
+        let mut idxs = Vec::default();
+        for (offset, step) in vec![
+            ([0., 0., 0.], [1., 0., 0.]),
+            ([0., 0.2, 0.], [1., 0., 0.]),
+            ([0., 0., 0.], [0., 1., 0.]),
+            ([5., 5., 5.], [0., 0., 1.]),
+        ]
+        .into_iter()
+        {
+            let neuron = RStarPointTangents::new(make_points(&offset, &step, 10), 5)
+                .expect("Construction failed");
+            idxs.push(arena.add_neuron(neuron));
+        }
+
+        let smat = ScoreMatrixBuilder::new(&arena, vec![0.5, 10.0], vec![0.5, 1.0])
+            .add_matching_set(&idxs[..2])
+            .add_nonmatching_set(&idxs)
+            .build()
+            .expect("Build failed");
+        assert_eq!(smat.shape(), (2, 2));
+
+        let close_parallel = smat.score(&DistDot {
+            dist: 0.2,
+            dot: 1.0,
+        });
+        let far_perpendicular = smat.score(&DistDot {
+            dist: 5.0,
+            dot: 0.0,
+        });
+        assert!(close_parallel > 0.0);
+        assert!(close_parallel > far_perpendicular);
+    }
+
+    #[test]
+    fn no_nonmatching() {
+        let score_fn = table_to_fn(vec![1.0], vec![1.0], vec![1.0]).expect("Invalid table");
+        let mut arena = NblastArena::new(score_fn, false);
+        let idx0 = arena.add_neuron(
+            RStarPointTangents::new(make_points(&[0., 0., 0.], &[1., 0., 0.], 10), 5)
+                .expect("Construction failed"),
+        );
+        let idx1 = arena.add_neuron(
+            RStarPointTangents::new(make_points(&[0., 1., 0.], &[1., 0., 0.], 10), 5)
+                .expect("Construction failed"),
+        );
+        let result = ScoreMatrixBuilder::new(&arena, vec![1.0], vec![1.0])
+            .add_matching_set(&[idx0, idx1])
+            .build();
+        match result {
+            Err(Error::InsufficientTrainingData(_)) => (),
+            _ => panic!("Expected InsufficientTrainingData"),
+        }
+    }
+
+    fn smat_path() -> std::path::PathBuf {
+        let mut path = std::path::PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        path.pop();
+        path.push("data");
+        path.push("smat_fcwb.csv");
+        path
+    }
+
+    #[test]
+    fn read_csv() {
+        let f = std::fs::File::open(smat_path()).expect("file not found");
+        let smat = ScoreMatrix::from_csv(f).expect("Could not read score matrix");
+        assert_eq!(smat.shape(), (21, 10));
+        assert_eq!(smat.dist_bins()[1], (0.75, 1.5));
+        assert_eq!(smat.dot_thresholds()[9], 1.0);
+        assert_eq!(smat.get(0, 0), Some(9.50009681841246));
+        assert_eq!(smat.get(21, 0), None);
+        assert_eq!(smat.max_score(), 11.3892297520051);
+        // outermost row depends on the dot product
+        assert_eq!(smat.max_dist(), None);
+        assert_eq!(
+            smat.score(&DistDot {
+                dist: 1000.0,
+                dot: 0.0
+            }),
+            smat.get(20, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn csv_roundtrip() {
+        let f = std::fs::File::open(smat_path()).expect("file not found");
+        let smat = ScoreMatrix::from_csv(f).expect("Could not read score matrix");
+        let mut buf = Vec::default();
+        smat.to_csv(&mut buf).expect("Could not write score matrix");
+        assert!(buf.starts_with(b",\"(0,0.1]\",\"(0.1,0.2]\""));
+        let smat2 = ScoreMatrix::from_csv(buf.as_slice()).expect("Could not reread score matrix");
+        assert_eq!(smat, smat2);
+    }
+
+    #[test]
+    fn max_dist() {
+        let with_outermost = |row: [Precision; 2]| {
+            let mut cells = vec![8.0, 4.0, 2.0, 1.0];
+            cells.extend(row.iter());
+            ScoreMatrix::new(vec![1.0, 2.0, 10.0], vec![0.5, 1.0], cells).expect("Invalid table")
+        };
+        assert_eq!(with_outermost([-1.0, -1.0]).max_dist(), Some(2.0));
+        assert_eq!(with_outermost([-1.0, -0.5]).max_dist(), None);
+        let single =
+            ScoreMatrix::new(vec![10.0], vec![0.5, 1.0], vec![10.0, 20.0]).expect("Invalid table");
+        assert_eq!(single.max_dist(), None);
+    }
+
+    #[test]
+    fn invalid_smat() {
+        assert!(ScoreMatrix::new(vec![1.0, 2.0], vec![1.0], vec![1.0]).is_err());
+        assert!(ScoreMatrix::new(vec![2.0, 1.0], vec![1.0], vec![1.0, 2.0]).is_err());
+        let bad_row = "\"\",\"(0,0.5]\",\"(0.5,1]\"\n\"(0,1]\",1.0\n";
+        match ScoreMatrix::from_csv(bad_row.as_bytes()) {
+            Err(Error::MalformedTable(_)) => (),
+            _ => panic!("Expected MalformedTable"),
+        }
+    }
+}
@@ -0,0 +1,869 @@
+//! Spatial indices for finding a target neuron's nearest point to each query point.
+//!
+//! Every index implements [SpatialIndex](trait.SpatialIndex.html),
+//! so that any of them can back an [IndexedPointTangents](../struct.IndexedPointTangents.html)
+//! with the same constructors.
+//! Which is fastest depends on the size and shape of the neurons:
+//!
+//! - [RStarIndex](struct.RStarIndex.html): an R*-tree, reusing the one built to calculate tangents
+//! - [KdTree](struct.KdTree.html): a static, balanced k-d tree
+//! - [GridHash](struct.GridHash.html): a hashed uniform grid
+//! - [BruteForce](struct.BruteForce.html): no index at all, for neurons of only a few points
+//!
+//! Each index owns its neuron's points, so that they are only stored once.
+//! - [DistanceField](struct.DistanceField.html): a precomputed grid of candidate points,
+//!   for targets which are queried many times
+use std::cmp::Ordering;
+use std::collections::HashSet;
+
+use rstar::primitives::PointWithData;
+use rstar::RTree;
+
+use nalgebra::ComplexField;
+
+use crate::{Error, Float};
+
+fn dist2<T: Float>(a: &[T; 3], b: &[T; 3]) -> T {
+    a.iter()
+        .zip(b.iter())
+        .fold(T::zero(), |sum, (x, y)| sum + (*x - *y) * (*x - *y))
+}
+
+/// The nearest point found so far, if any within the maximum distance.
+struct Candidate<T> {
+    best: Option<(usize, T)>,
+    max_dist2: Option<T>,
+}
+
+impl<T: Float> Candidate<T> {
+    fn new(max_dist: Option<T>) -> Self {
+        Self {
+            best: None,
+            max_dist2: max_dist.map(|d| d * d),
+        }
+    }
+
+    /// Replace the candidate if the point is nearer than it, and within the maximum distance.
+    /// Ties go to the earlier candidate.
+    fn offer(&mut self, pos: usize, d2: T) {
+        let nearer = match (self.best, self.max_dist2) {
+            (Some((_, best_d2)), _) => d2 < best_d2,
+            (None, Some(max_d2)) => d2 <= max_d2,
+            (None, None) => true,
+        };
+        if nearer {
+            self.best = Some((pos, d2));
+        }
+    }
+
+    /// Whether points at least this squared distance away cannot be the nearest.
+    fn excludes(&self, d2: T) -> bool {
+        match self.best.map(|(_, best_d2)| best_d2).or(self.max_dist2) {
+            Some(bound) => d2 > bound,
+            None => false,
+        }
+    }
+}
+
+/// Spatial index over a target neuron's points, which do not change after construction.
+/// The index owns the points, and may store them in any order.
+pub trait SpatialIndex<T: Float>: Sized {
+    /// Build the index over the given points, identified by their position in the vector.
+    fn from_points(points: Vec<[T; 3]>) -> Self;
+
+    /// Build the index from the R*-tree which was already built to calculate tangents,
+    /// where each point's data is its index in `points`.
+    /// By default, the tree is discarded and the index is built from `points`.
+    fn from_rtree(_rtree: RTree<PointWithData<usize, [T; 3]>>, points: Vec<[T; 3]>) -> Self {
+        Self::from_points(points)
+    }
+
+    /// Number of points.
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Each point's index and position, in the order they are stored.
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_>;
+
+    /// Copy of the points, in their original order.
+    fn points(&self) -> Vec<[T; 3]> {
+        let mut out = vec![[T::zero(); 3]; self.len()];
+        for (idx, point) in self.iter_points() {
+            out[idx] = *point;
+        }
+        out
+    }
+
+    /// Find the index and squared distance of the nearest point to `point`,
+    /// or `None` if there are no points within `max_dist` (if given).
+    /// Indices can stop searching beyond `max_dist`.
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)>;
+}
+
+/// [R*-tree](https://en.wikipedia.org/wiki/R*_tree), the original backend.
+/// Reuses the tree built to calculate tangents, so is the fastest to construct.
+#[derive(Clone)]
+pub struct RStarIndex<T: Float>(RTree<PointWithData<usize, [T; 3]>>);
+
+impl<T: Float> SpatialIndex<T> for RStarIndex<T> {
+    fn from_points(points: Vec<[T; 3]>) -> Self {
+        Self(RTree::bulk_load(
+            points
+                .into_iter()
+                .enumerate()
+                .map(|(idx, point)| PointWithData::new(idx, point))
+                .collect(),
+        ))
+    }
+
+    /// The tree already holds the points, so `points` is dropped.
+    fn from_rtree(rtree: RTree<PointWithData<usize, [T; 3]>>, _points: Vec<[T; 3]>) -> Self {
+        Self(rtree)
+    }
+
+    fn len(&self) -> usize {
+        self.0.size()
+    }
+
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_> {
+        Box::new(self.0.iter().map(|p| (p.data, p.position())))
+    }
+
+    /// With a maximum distance, only the subtrees within it are searched.
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        match max_dist {
+            Some(m) => {
+                let mut candidate = Candidate::new(max_dist);
+                for element in self.within(point, m) {
+                    candidate.offer(element.data, dist2(element.position(), point));
+                }
+                candidate.best
+            }
+            None => self
+                .0
+                .nearest_neighbor_iter_with_distance(point)
+                .next()
+                .map(|(element, dist2)| (element.data, dist2)),
+        }
+    }
+}
+
+impl<T: Float> RStarIndex<T> {
+    /// The points within `max_dist` of `point`.
+    fn within<'a>(
+        &'a self,
+        point: &[T; 3],
+        max_dist: T,
+    ) -> impl Iterator<Item = &'a PointWithData<usize, [T; 3]>> + 'a {
+        self.0.locate_within_distance(*point, max_dist * max_dist)
+    }
+}
+
+/// Subtrees with at most this many points are searched linearly.
+const KD_LEAF_SIZE: usize = 8;
+
+/// Static k-d tree, stored implicitly in a single array:
+/// each subtree is a contiguous range, split at its median point
+/// along the dimension in which it is most spread out.
+#[derive(Clone)]
+pub struct KdTree<T: Float> {
+    /// Points reordered into the tree.
+    points: Vec<[T; 3]>,
+    /// Original index of each reordered point.
+    idxs: Vec<usize>,
+    /// Splitting dimension of the subtree whose median is at this position.
+    dims: Vec<u8>,
+}
+
+impl<T: Float> KdTree<T> {
+    fn build(items: &mut [(usize, [T; 3])], dims: &mut [u8]) {
+        if items.len() <= KD_LEAF_SIZE {
+            return;
+        }
+        let mut lower = items[0].1;
+        let mut upper = items[0].1;
+        for (_, p) in items.iter() {
+            for dim in 0..3 {
+                lower[dim] = lower[dim].min(p[dim]);
+                upper[dim] = upper[dim].max(p[dim]);
+            }
+        }
+        let mut split = 0;
+        for dim in 1..3 {
+            if upper[dim] - lower[dim] > upper[split] - lower[split] {
+                split = dim;
+            }
+        }
+        items.sort_unstable_by(|a, b| {
+            a.1[split]
+                .partial_cmp(&b.1[split])
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let mid = items.len() / 2;
+        dims[mid] = split as u8;
+        let (left_items, right_items) = items.split_at_mut(mid);
+        let (left_dims, right_dims) = dims.split_at_mut(mid);
+        Self::build(left_items, left_dims);
+        Self::build(&mut right_items[1..], &mut right_dims[1..]);
+    }
+
+    fn search(&self, lo: usize, hi: usize, point: &[T; 3], candidate: &mut Candidate<T>) {
+        if hi - lo <= KD_LEAF_SIZE {
+            for pos in lo..hi {
+                candidate.offer(pos, dist2(&self.points[pos], point));
+            }
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        candidate.offer(mid, dist2(&self.points[mid], point));
+        let dim = self.dims[mid] as usize;
+        let diff = point[dim] - self.points[mid][dim];
+        let (near, far) = if diff < T::zero() {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+        self.search(near.0, near.1, point, candidate);
+        if !candidate.excludes(diff * diff) {
+            self.search(far.0, far.1, point, candidate);
+        }
+    }
+}
+
+impl<T: Float> KdTree<T> {
+    /// Position in the tree and squared distance of the nearest point.
+    fn nearest_pos(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        let mut candidate = Candidate::new(max_dist);
+        self.search(0, self.points.len(), point, &mut candidate);
+        candidate.best
+    }
+
+    /// Push the positions in the tree of all points within `dist2_max` squared distance of `point`.
+    fn within(&self, lo: usize, hi: usize, point: &[T; 3], dist2_max: T, out: &mut Vec<usize>) {
+        if hi - lo <= KD_LEAF_SIZE {
+            for pos in lo..hi {
+                if dist2(&self.points[pos], point) <= dist2_max {
+                    out.push(pos);
+                }
+            }
+            return;
+        }
+        let mid = (lo + hi) / 2;
+        if dist2(&self.points[mid], point) <= dist2_max {
+            out.push(mid);
+        }
+        let dim = self.dims[mid] as usize;
+        let diff = point[dim] - self.points[mid][dim];
+        if diff <= T::zero() || diff * diff <= dist2_max {
+            self.within(lo, mid, point, dist2_max, out);
+        }
+        if diff >= T::zero() || diff * diff <= dist2_max {
+            self.within(mid + 1, hi, point, dist2_max, out);
+        }
+    }
+}
+
+impl<T: Float> SpatialIndex<T> for KdTree<T> {
+    fn from_points(points: Vec<[T; 3]>) -> Self {
+        let mut items: Vec<_> = points.into_iter().enumerate().collect();
+        let mut dims = vec![0; items.len()];
+        Self::build(&mut items, &mut dims);
+        let (idxs, points) = items.into_iter().unzip();
+        Self { points, idxs, dims }
+    }
+
+    fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_> {
+        Box::new(self.idxs.iter().cloned().zip(self.points.iter()))
+    }
+
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        self.nearest_pos(point, max_dist)
+            .map(|(pos, d2)| (self.idxs[pos], d2))
+    }
+}
+
+/// The grid's cell size is halved until there are at most this many points per occupied cell.
+const GRID_OCCUPANCY: usize = 16;
+/// Limit on how many times the cell size is halved.
+const GRID_MAX_REFINEMENTS: usize = 16;
+/// Cell coordinates are clamped to this magnitude before conversion to integers.
+const GRID_MAX_CELL: f64 = 1e12;
+
+/// Uniform grid of cubic cells, whose points are stored in a hash table
+/// with about two buckets per occupied cell.
+/// The nearest point is found by searching shells of cells of increasing size
+/// around the query point's cell.
+///
+/// The cell size is chosen from the points' extent and density,
+/// so this works best for neurons which are sampled at a roughly even spacing.
+#[derive(Clone)]
+pub struct GridHash<T: Float> {
+    origin: [T; 3],
+    cell_size: T,
+    /// Largest cell coordinates in each dimension; the smallest are 0.
+    max_cell: [i64; 3],
+    /// Start of each bucket's range of `points`, followed by the end of the last bucket.
+    /// The number of buckets is a power of 2.
+    starts: Vec<usize>,
+    /// Points sorted by bucket.
+    points: Vec<[T; 3]>,
+    /// Original index of each sorted point.
+    idxs: Vec<usize>,
+}
+
+fn cell_of<T: Float>(origin: &[T; 3], cell_size: T, point: &[T; 3]) -> [i64; 3] {
+    let mut out = [0; 3];
+    for dim in 0..3 {
+        let c = ((point[dim] - origin[dim]) / cell_size)
+            .floor()
+            .to_precision();
+        out[dim] = (c.signum() * c.abs().min(GRID_MAX_CELL)) as i64;
+    }
+    out
+}
+
+/// Spatial hash of Teschner et al. (2003), with a mask for a power-of-2 number of buckets.
+fn hash_cell(cell: &[i64; 3], mask: usize) -> usize {
+    let h = (cell[0] as u64).wrapping_mul(73_856_093)
+        ^ (cell[1] as u64).wrapping_mul(19_349_663)
+        ^ (cell[2] as u64).wrapping_mul(83_492_791);
+    h as usize & mask
+}
+
+impl<T: Float> GridHash<T> {
+    fn choose_cell_size(points: &[[T; 3]], origin: &[T; 3], extent: T) -> T {
+        if extent <= T::zero() {
+            return T::one();
+        }
+        let mut cell_size = extent / T::from_precision(points.len() as f64).cbrt();
+        for _ in 0..GRID_MAX_REFINEMENTS {
+            let occupied: HashSet<_> = points
+                .iter()
+                .map(|p| cell_of(origin, cell_size, p))
+                .collect();
+            if points.len() <= occupied.len() * GRID_OCCUPANCY {
+                break;
+            }
+            cell_size /= T::from_precision(2.0);
+        }
+        cell_size
+    }
+
+    /// Check every point in the cell's bucket,
+    /// which may include points from other cells with the same hash.
+    fn search_cell(&self, cell: &[i64; 3], point: &[T; 3], candidate: &mut Candidate<T>) {
+        let bucket = hash_cell(cell, self.starts.len() - 2);
+        for pos in self.starts[bucket]..self.starts[bucket + 1] {
+            candidate.offer(pos, dist2(&self.points[pos], point));
+        }
+    }
+
+    /// Search the cells whose largest coordinate difference from `centre` is `radius`.
+    fn search_shell(
+        &self,
+        centre: &[i64; 3],
+        radius: i64,
+        point: &[T; 3],
+        candidate: &mut Candidate<T>,
+    ) {
+        let range = |dim: usize| {
+            (centre[dim] - radius).max(0)..=(centre[dim] + radius).min(self.max_cell[dim])
+        };
+        for x in range(0) {
+            for y in range(1) {
+                let on_shell = (x - centre[0]).abs() == radius || (y - centre[1]).abs() == radius;
+                if on_shell {
+                    for z in range(2) {
+                        self.search_cell(&[x, y, z], point, candidate);
+                    }
+                } else {
+                    for z in &[centre[2] - radius, centre[2] + radius] {
+                        if *z >= 0 && *z <= self.max_cell[2] {
+                            self.search_cell(&[x, y, *z], point, candidate);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl<T: Float> SpatialIndex<T> for GridHash<T> {
+    fn from_points(points: Vec<[T; 3]>) -> Self {
+        let mut origin = points.first().cloned().unwrap_or([T::zero(); 3]);
+        let mut upper = origin;
+        for p in points.iter() {
+            for dim in 0..3 {
+                origin[dim] = origin[dim].min(p[dim]);
+                upper[dim] = upper[dim].max(p[dim]);
+            }
+        }
+        let extent = (0..3).fold(T::zero(), |e, dim| e.max(upper[dim] - origin[dim]));
+        let cell_size = Self::choose_cell_size(&points, &origin, extent);
+
+        let cells: Vec<_> = points
+            .iter()
+            .map(|p| cell_of(&origin, cell_size, p))
+            .collect();
+        let occupied: HashSet<_> = cells.iter().collect();
+        let n_buckets = (occupied.len() * 2).next_power_of_two();
+        let mut keyed: Vec<_> = cells
+            .iter()
+            .map(|c| hash_cell(c, n_buckets - 1))
+            .zip(points.into_iter().enumerate())
+            .collect();
+        keyed.sort_unstable_by_key(|(bucket, (idx, _))| (*bucket, *idx));
+
+        let mut starts = vec![0; n_buckets + 1];
+        for (bucket, _) in keyed.iter() {
+            starts[*bucket + 1] += 1;
+        }
+        for bucket in 0..n_buckets {
+            starts[bucket + 1] += starts[bucket];
+        }
+        let (idxs, points) = keyed.into_iter().map(|(_, item)| item).unzip();
+        Self {
+            origin,
+            cell_size,
+            max_cell: cell_of(&origin, cell_size, &upper),
+            starts,
+            points,
+            idxs,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_> {
+        Box::new(self.idxs.iter().cloned().zip(self.points.iter()))
+    }
+
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        let centre = cell_of(&self.origin, self.cell_size, point);
+        // shells nearer than the grid are empty, and shells further than its far corner are outside it
+        let mut first = 0;
+        let mut last = 0;
+        for (c, max_c) in centre.iter().zip(self.max_cell.iter()) {
+            first = first.max(-c).max(c - max_c);
+            last = last.max(*c).max(max_c - c);
+        }
+
+        let mut candidate = Candidate::new(max_dist);
+        for radius in first..=last {
+            // points in this shell are at least this far away
+            let bound = self.cell_size * T::from_precision((radius - 1).max(0) as f64);
+            if candidate.excludes(bound * bound) {
+                break;
+            }
+            let n_cells = (0..3).fold(1, |n, dim| {
+                let lo = (centre[dim] - radius).max(0);
+                let hi = (centre[dim] + radius).min(self.max_cell[dim]);
+                n * (hi - lo + 1).max(0)
+            });
+            // the cube up to this shell contains every cell searched so far
+            if n_cells as usize > self.points.len() {
+                // far from the points, checking all of them is cheaper than searching more cells
+                for (pos, p) in self.points.iter().enumerate() {
+                    candidate.offer(pos, dist2(p, point));
+                }
+                break;
+            }
+            self.search_shell(&centre, radius, point, &mut candidate);
+        }
+        candidate.best.map(|(pos, d2)| (self.idxs[pos], d2))
+    }
+}
+
+/// Memory budget per point of a distance field built with
+/// [from_points](trait.SpatialIndex.html#tymethod.from_points).
+const FIELD_DEFAULT_BYTES_PER_POINT: usize = 1024;
+/// Smallest default memory budget, which fits the coarsest grid even if every cell
+/// stores the most candidates.
+const FIELD_MIN_DEFAULT_BYTES: usize = 2048;
+/// Cells with more candidates than this use the fallback tree instead.
+const FIELD_MAX_CANDIDATES: usize = 16;
+/// Guess at the average number of candidates per cell, used to choose the initial cell size.
+const FIELD_CANDIDATES_GUESS: usize = 4;
+/// Largest number of points or candidates, which are stored as `u32`.
+const FIELD_MAX_LEN: usize = 0xFFFF_FFFF;
+/// Candidate distances are padded by this fraction of the cell size,
+/// so that rounding errors cannot exclude the nearest point.
+const FIELD_SLACK: f64 = 0.01;
+
+/// Voxelised distance field: a grid over the points' bounding box, with a margin of one cell,
+/// which stores every point which could be the nearest to some location in each cell.
+/// Finding the nearest point means checking the few candidates of a single cell,
+/// rather than traversing a tree.
+/// Locations outside of the grid, and cells with too many candidates to be worth storing
+/// (typically those far from every point), fall back to a [KdTree](struct.KdTree.html).
+///
+/// Every location in a cell is at most some distance `u` from the point nearest to its centre,
+/// so no point further than `u` from the whole cell can be the nearest to any location in it.
+/// All other points are that cell's candidates, so the result is exact.
+///
+/// Building the grid is slow and it can use much more memory than other indices,
+/// so it is best suited to targets which are queried many times.
+#[derive(Clone)]
+pub struct DistanceField<T: Float> {
+    origin: [T; 3],
+    cell_size: T,
+    shape: [usize; 3],
+    /// Start of each cell's range of `candidates`, followed by the end of the last cell's.
+    starts: Vec<u32>,
+    /// Positions in the fallback tree of each cell's candidate points, in ascending order.
+    candidates: Vec<u32>,
+    /// Also holds the points.
+    fallback: KdTree<T>,
+}
+
+/// Smallest and largest squared distances from the point to any location in the cube.
+fn box_dist2<T: Float>(lower: &[T; 3], size: T, point: &[T; 3]) -> (T, T) {
+    let mut min2 = T::zero();
+    let mut max2 = T::zero();
+    for (lo, p) in lower.iter().zip(point.iter()) {
+        let below = *lo - *p;
+        let above = *p - (*lo + size);
+        let min = below.max(above).max(T::zero());
+        let max = ComplexField::abs(below).max(ComplexField::abs(above));
+        min2 += min * min;
+        max2 += max * max;
+    }
+    (min2, max2)
+}
+
+impl<T: Float> DistanceField<T> {
+    /// Build a distance field whose grid uses at most `memory_budget` bytes,
+    /// not including the fallback tree.
+    /// The cell size is doubled until the grid fits in the budget.
+    ///
+    /// Fails if the budget is too small for even a grid of 3 cells in each dimension.
+    pub fn new(points: &[[T; 3]], memory_budget: usize) -> Result<Self, Error> {
+        if points.len() > FIELD_MAX_LEN {
+            return Err(Error::InvalidParameter(
+                "Too many points for a distance field",
+            ));
+        }
+        let mut lower = points.first().cloned().unwrap_or([T::zero(); 3]);
+        let mut upper = lower;
+        for p in points.iter() {
+            for dim in 0..3 {
+                lower[dim] = lower[dim].min(p[dim]);
+                upper[dim] = upper[dim].max(p[dim]);
+            }
+        }
+        let extent = (0..3).fold(T::zero(), |e, dim| e.max(upper[dim] - lower[dim]));
+        let max_cells = memory_budget / (4 * (1 + FIELD_CANDIDATES_GUESS));
+        let mut cell_size = if extent > T::zero() && max_cells > 0 {
+            extent / T::from_precision(max_cells as f64).cbrt()
+        } else {
+            T::one()
+        };
+
+        let mut fallback = KdTree::from_points(points.to_vec());
+        loop {
+            let mut origin = lower;
+            let mut shape = [0; 3];
+            for dim in 0..3 {
+                origin[dim] -= cell_size;
+                shape[dim] = ((upper[dim] - lower[dim]) / cell_size)
+                    .floor()
+                    .to_precision() as usize
+                    + 3;
+            }
+            fallback = match Self::build(fallback, origin, cell_size, shape, memory_budget) {
+                Ok(field) => return Ok(field),
+                Err(_) if shape == [3, 3, 3] => {
+                    return Err(Error::InvalidParameter(
+                        "Memory budget is too small for a distance field",
+                    ))
+                }
+                Err(fallback) => fallback,
+            };
+            cell_size *= T::from_precision(2.0);
+        }
+    }
+
+    /// Build the grid with the given cell size and shape,
+    /// or give back the fallback tree if it does not fit in the budget.
+    fn build(
+        fallback: KdTree<T>,
+        origin: [T; 3],
+        cell_size: T,
+        shape: [usize; 3],
+        memory_budget: usize,
+    ) -> Result<Self, KdTree<T>> {
+        let n_cells = shape[0] * shape[1] * shape[2];
+        let max_len = (memory_budget / 4).min(FIELD_MAX_LEN);
+        if n_cells + 1 > max_len {
+            return Err(fallback);
+        }
+        let half = T::from_precision(0.5);
+        let half_diagonal = cell_size * half * T::from_precision(3.0).sqrt();
+        let slack = cell_size * T::from_precision(FIELD_SLACK);
+
+        let mut starts = Vec::with_capacity(n_cells + 1);
+        starts.push(0);
+        let mut candidates = Vec::default();
+        let mut found = Vec::default();
+        for x in 0..shape[0] {
+            for y in 0..shape[1] {
+                for z in 0..shape[2] {
+                    let mut lower = origin;
+                    for (dim, idx) in [x, y, z].iter().enumerate() {
+                        lower[dim] += cell_size * T::from_precision(*idx as f64);
+                    }
+                    let mut centre = lower;
+                    for c in centre.iter_mut() {
+                        *c += cell_size * half;
+                    }
+                    found.clear();
+                    if let Some((nearest, _)) = fallback.nearest_pos(&centre, None) {
+                        let points = &fallback.points;
+                        let (_, max2) = box_dist2(&lower, cell_size, &points[nearest]);
+                        let max_dist = max2.sqrt() + slack;
+                        let radius = max_dist + half_diagonal;
+                        fallback.within(0, points.len(), &centre, radius * radius, &mut found);
+                        found.retain(|pos| {
+                            box_dist2(&lower, cell_size, &points[*pos]).0 <= max_dist * max_dist
+                        });
+                        if found.len() > FIELD_MAX_CANDIDATES {
+                            // use the fallback tree instead
+                            found.clear();
+                        }
+                        found.sort_unstable();
+                    }
+                    candidates.extend(found.iter().map(|pos| *pos as u32));
+                    if n_cells + 1 + candidates.len() > max_len {
+                        return Err(fallback);
+                    }
+                    starts.push(candidates.len() as u32);
+                }
+            }
+        }
+        Ok(Self {
+            origin,
+            cell_size,
+            shape,
+            starts,
+            candidates,
+            fallback,
+        })
+    }
+
+    /// Bytes used by the grid, not including the fallback tree.
+    pub fn grid_bytes(&self) -> usize {
+        (self.starts.len() + self.candidates.len()) * 4
+    }
+
+    /// Linear index of the cell containing the point, if it is in the grid.
+    fn cell_idx(&self, point: &[T; 3]) -> Option<usize> {
+        let mut idx = 0;
+        for ((p, o), n) in point.iter().zip(self.origin.iter()).zip(self.shape.iter()) {
+            let c = (*p - *o) / self.cell_size;
+            // also rejects NaN
+            if !(c >= T::zero() && c < T::from_precision(*n as f64)) {
+                return None;
+            }
+            idx = idx * n + c.floor().to_precision() as usize;
+        }
+        Some(idx)
+    }
+}
+
+impl<T: Float> SpatialIndex<T> for DistanceField<T> {
+    /// Build with a memory budget proportional to the number of points.
+    /// Use [new](#method.new) to choose the budget.
+    fn from_points(points: Vec<[T; 3]>) -> Self {
+        let budget = (points.len() * FIELD_DEFAULT_BYTES_PER_POINT).max(FIELD_MIN_DEFAULT_BYTES);
+        Self::new(&points, budget).expect("default budget fits the coarsest grid")
+    }
+
+    fn len(&self) -> usize {
+        self.fallback.len()
+    }
+
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_> {
+        self.fallback.iter_points()
+    }
+
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        let range = match self.cell_idx(point) {
+            Some(c) if self.starts[c + 1] > self.starts[c] => {
+                self.starts[c] as usize..self.starts[c + 1] as usize
+            }
+            _ => return self.fallback.nearest(point, max_dist),
+        };
+        let mut candidate = Candidate::new(max_dist);
+        for pos in self.candidates[range].iter() {
+            let pos = *pos as usize;
+            candidate.offer(pos, dist2(&self.fallback.points[pos], point));
+        }
+        candidate
+            .best
+            .map(|(pos, d2)| (self.fallback.idxs[pos], d2))
+    }
+}
+
+/// Compare against every point: fastest for neurons of only a few points.
+#[derive(Debug, Clone)]
+pub struct BruteForce<T: Float>(Vec<[T; 3]>);
+
+impl<T: Float> SpatialIndex<T> for BruteForce<T> {
+    fn from_points(points: Vec<[T; 3]>) -> Self {
+        Self(points)
+    }
+
+    fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    fn iter_points(&self) -> Box<dyn Iterator<Item = (usize, &[T; 3])> + '_> {
+        Box::new(self.0.iter().enumerate())
+    }
+
+    fn points(&self) -> Vec<[T; 3]> {
+        self.0.clone()
+    }
+
+    fn nearest(&self, point: &[T; 3], max_dist: Option<T>) -> Option<(usize, T)> {
+        let mut candidate = Candidate::new(max_dist);
+        for (idx, p) in self.0.iter().enumerate() {
+            candidate.offer(idx, dist2(p, point));
+        }
+        candidate.best
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Deterministic points along a noisy helix, with some duplicates.
+    fn helix(count: usize) -> Vec<[f64; 3]> {
+        let mut state: u64 = 12345;
+        let mut noise = || {
+            state = state
+                .wrapping_mul(6_364_136_223_846_793_005)
+                .wrapping_add(1);
+            ((state >> 33) as f64 / (1u64 << 31) as f64) - 0.5
+        };
+        let mut out: Vec<[f64; 3]> = (0..count)
+            .map(|i| {
+                let t = i as f64 * 0.1;
+                [
+                    t.cos() * 10.0 + noise(),
+                    t.sin() * 10.0 + noise(),
+                    t + noise(),
+                ]
+            })
+            .collect();
+        out.extend(out[..count / 10].to_vec());
+        out
+    }
+
+    fn check_index<I: SpatialIndex<f64>>(points: &[[f64; 3]], queries: &[[f64; 3]]) {
+        let index = I::from_points(points.to_vec());
+        assert_eq!(index.len(), points.len());
+        assert_eq!(index.points(), points);
+        let brute_force = BruteForce::from_points(points.to_vec());
+        for q in queries.iter() {
+            for max_dist in [None, Some(1.0), Some(30.0)].iter() {
+                let expected = brute_force.nearest(q, *max_dist);
+                match (index.nearest(q, *max_dist), expected) {
+                    (Some((idx, d2)), Some((_, expected_d2))) => {
+                        assert_eq!(d2, expected_d2);
+                        assert_eq!(dist2(&points[idx], q), d2);
+                    }
+                    (None, None) => (),
+                    (found, _) => panic!("Expected {:?}, got {:?}", expected, found),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn indices_agree() {
+        let points = helix(500);
+        let mut queries: Vec<_> = helix(200).iter().map(|p| [p[1], p[0], p[2]]).collect();
+        queries.push([1000.0, -1000.0, 0.0]);
+        queries.push([0.0, 0.0, -50.0]);
+        queries.push(points[17]);
+
+        check_index::<RStarIndex<f64>>(&points, &queries);
+        check_index::<KdTree<f64>>(&points, &queries);
+        check_index::<GridHash<f64>>(&points, &queries);
+        check_index::<DistanceField<f64>>(&points, &queries);
+        check_index::<KdTree<f64>>(&points[..3], &queries);
+        check_index::<GridHash<f64>>(&points[..1], &queries);
+        check_index::<DistanceField<f64>>(&points[..1], &queries);
+
+        let empty: Vec<[f64; 3]> = Vec::default();
+        assert!(KdTree::from_points(empty.clone())
+            .nearest(&[0.0; 3], None)
+            .is_none());
+        assert!(DistanceField::from_points(empty.clone())
+            .nearest(&[0.0; 3], None)
+            .is_none());
+        assert!(GridHash::from_points(empty)
+            .nearest(&[0.0; 3], None)
+            .is_none());
+    }
+
+    #[test]
+    fn rstar_stops_at_max_dist() {
+        let points = helix(500);
+        let index = RStarIndex::from_points(points.clone());
+        // inside the helix's bounding box, but far from most of its points
+        let query = [10.0, 0.0, 25.0];
+        let max_dist = 3.0;
+        let visited: Vec<_> = index.within(&query, max_dist).collect();
+        let in_range = points
+            .iter()
+            .filter(|p| dist2(p, &query) <= max_dist * max_dist)
+            .count();
+        assert!(in_range > 0);
+        assert!(in_range < points.len() / 10);
+        assert_eq!(visited.len(), in_range);
+        assert_eq!(
+            index.nearest(&query, Some(max_dist)),
+            BruteForce::from_points(points).nearest(&query, Some(max_dist))
+        );
+    }
+
+    #[test]
+    fn non_finite_queries() {
+        // targets rely on a non-empty index always finding a point without a maximum distance
+        let points = helix(100);
+        let queries = [[f64::NAN, 0.0, 0.0], [f64::INFINITY, 0.0, 0.0], [f64::NAN; 3]];
+        for q in queries.iter() {
+            assert!(RStarIndex::from_points(points.clone()).nearest(q, None).is_some());
+            assert!(KdTree::from_points(points.clone()).nearest(q, None).is_some());
+            assert!(GridHash::from_points(points.clone()).nearest(q, None).is_some());
+            assert!(DistanceField::from_points(points.clone()).nearest(q, None).is_some());
+            assert!(BruteForce::from_points(points.clone()).nearest(q, None).is_some());
+        }
+    }
+
+    #[test]
+    fn distance_field_budget() {
+        let points = helix(500);
+        match DistanceField::new(&points, 100) {
+            Err(Error::InvalidParameter(_)) => (),
+            _ => panic!("Expected InvalidParameter error"),
+        }
+        for budget in [100_000, 1_000_000].iter() {
+            let field = DistanceField::new(&points, *budget).expect("Could not build field");
+            assert!(field.grid_bytes() <= *budget);
+        }
+    }
+}
@@ -0,0 +1,27 @@
+//! Fixtures shared between the unit tests of different modules.
+use crate::Precision;
+
+fn add_points(a: &[Precision; 3], b: &[Precision; 3]) -> [Precision; 3] {
+    let mut out = [0., 0., 0.];
+    for (idx, (x, y)) in a.iter().zip(b.iter()).enumerate() {
+        out[idx] = x + y;
+    }
+    out
+}
+
+/// `count` evenly spaced points in a straight line from `offset`.
+pub(crate) fn make_points(
+    offset: &[Precision; 3],
+    step: &[Precision; 3],
+    count: usize,
+) -> Vec<[Precision; 3]> {
+    let mut out = Vec::default();
+    out.push(*offset);
+
+    for _ in 0..count - 1 {
+        let to_push = add_points(out.last().unwrap(), step);
+        out.push(to_push);
+    }
+
+    out
+}
@@ -0,0 +1,583 @@
+//! Transformations of neurons, e.g. to mirror them into the other hemisphere
+//! or to move them between registrations.
+use nalgebra::base::{DMatrix, Matrix3, Matrix4, Unit, Vector3};
+
+use crate::spatial::SpatialIndex;
+use crate::{
+    Error, Float, IndexedPointTangents, Neighborhood, Precision, QueryNeuron, TargetNeuron,
+};
+
+/// Affine transformation of points, tangents and neurons:
+/// a linear map followed by a translation.
+///
+/// Tangents are only transformed by the linear map, and renormalised.
+/// Alpha values are kept, which is exact for rigid transformations and mirrors
+/// but only approximate for transformations which scale unevenly or shear.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Transform<T: Float = Precision> {
+    linear: Matrix3<T>,
+    translation: Vector3<T>,
+}
+
+impl<T: Float> Transform<T> {
+    /// The linear map must be finite and invertible, so that tangents are never collapsed.
+    pub fn new(linear: Matrix3<T>, translation: Vector3<T>) -> Result<Self, Error> {
+        if linear
+            .iter()
+            .chain(translation.iter())
+            .any(|v| !v.is_finite())
+        {
+            return Err(Error::InvalidParameter("Transform must be finite"));
+        }
+        if linear.try_inverse().is_none() {
+            return Err(Error::InvalidParameter("Transform must be invertible"));
+        }
+        Ok(Self {
+            linear,
+            translation,
+        })
+    }
+
+    /// From a 4x4 matrix in homogeneous coordinates, which applies to column vectors.
+    /// The bottom row must be `[0, 0, 0, 1]`.
+    pub fn from_matrix(matrix: &Matrix4<T>) -> Result<Self, Error> {
+        let bottom = matrix.fixed_slice::<nalgebra::U1, nalgebra::U4>(3, 0);
+        if bottom[0] != T::zero()
+            || bottom[1] != T::zero()
+            || bottom[2] != T::zero()
+            || bottom[3] != T::one()
+        {
+            return Err(Error::InvalidParameter(
+                "Bottom row of affine matrix must be [0, 0, 0, 1]",
+            ));
+        }
+        Self::new(
+            matrix
+                .fixed_slice::<nalgebra::U3, nalgebra::U3>(0, 0)
+                .into(),
+            matrix
+                .fixed_slice::<nalgebra::U3, nalgebra::U1>(0, 3)
+                .into(),
+        )
+    }
+
+    /// Transformation which changes nothing.
+    pub fn identity() -> Self {
+        Self {
+            linear: Matrix3::identity(),
+            translation: Vector3::zeros(),
+        }
+    }
+
+    /// Reflection across the plane through `point` with the (not necessarily unit) `normal`,
+    /// e.g. the midline of a brain template.
+    pub fn mirror(point: &[T; 3], normal: &[T; 3]) -> Result<Self, Error> {
+        let normal = Vector3::new(normal[0], normal[1], normal[2]);
+        let norm = normal.norm();
+        if norm <= T::zero() || !norm.is_finite() {
+            return Err(Error::InvalidParameter(
+                "Mirror plane normal must be non-zero and finite",
+            ));
+        }
+        let normal = normal / norm;
+        let offset = normal.dot(&Vector3::new(point[0], point[1], point[2]));
+        let two = T::one() + T::one();
+        Self::new(
+            Matrix3::identity() - normal * normal.transpose() * two,
+            normal * offset * two,
+        )
+    }
+
+    /// The 4x4 matrix in homogeneous coordinates.
+    pub fn to_matrix(&self) -> Matrix4<T> {
+        let mut matrix = Matrix4::identity();
+        matrix
+            .fixed_slice_mut::<nalgebra::U3, nalgebra::U3>(0, 0)
+            .copy_from(&self.linear);
+        matrix
+            .fixed_slice_mut::<nalgebra::U3, nalgebra::U1>(0, 3)
+            .copy_from(&self.translation);
+        matrix
+    }
+
+    /// Transformation which applies this one, then `other`.
+    pub fn then(&self, other: &Self) -> Self {
+        Self {
+            linear: other.linear * self.linear,
+            translation: other.linear * self.translation + other.translation,
+        }
+    }
+
+    /// Transformation which undoes this one.
+    pub fn inverse(&self) -> Self {
+        let linear = self
+            .linear
+            .try_inverse()
+            .expect("linear map was checked to be invertible");
+        Self {
+            linear,
+            translation: -(linear * self.translation),
+        }
+    }
+
+    /// Apply the linear map and translation to a point.
+    pub fn transform_point(&self, point: &[T; 3]) -> [T; 3] {
+        let p = self.linear * Vector3::new(point[0], point[1], point[2]) + self.translation;
+        [p[0], p[1], p[2]]
+    }
+
+    /// Apply only the linear map to a tangent, and renormalise it.
+    pub fn transform_tangent(&self, tangent: &Unit<Vector3<T>>) -> Unit<Vector3<T>> {
+        Unit::new_normalize(self.linear * tangent.into_inner())
+    }
+
+    /// Transform the points and tangents of any neuron into a new target neuron,
+    /// e.g. an [RStarPointTangents](type.RStarPointTangents.html).
+    ///
+    /// Fails if the transformed points are too large to represent.
+    pub fn transform_neuron<I: SpatialIndex<T>>(
+        &self,
+        neuron: &impl QueryNeuron<T>,
+    ) -> Result<IndexedPointTangents<T, I>, Error> {
+        IndexedPointTangents::new_with_tangents_alphas(
+            neuron
+                .points()
+                .iter()
+                .map(|p| self.transform_point(p))
+                .collect(),
+            neuron
+                .tangents()
+                .iter()
+                .map(|t| self.transform_tangent(t))
+                .collect(),
+            neuron.alphas(),
+        )
+    }
+}
+
+impl<T: Float> Default for Transform<T> {
+    fn default() -> Self {
+        Self::identity()
+    }
+}
+
+/// Default maximum number of [Icp](struct.Icp.html) iterations.
+const ICP_MAX_ITERATIONS: usize = 50;
+/// Default relative improvement in RMS distance below which [Icp](struct.Icp.html) stops.
+const ICP_TOLERANCE: Precision = 1e-6;
+/// Fewest point pairs which determine a rigid transformation.
+const ICP_MIN_PAIRS: usize = 3;
+
+/// Rigid [iterative closest point](https://en.wikipedia.org/wiki/Iterative_closest_point)
+/// alignment of a query neuron to a target neuron,
+/// to correct small registration offsets before scoring.
+///
+/// Each iteration pairs every moved query point with its nearest target point,
+/// using the target's spatial index,
+/// and then finds the rotation and translation which best superimposes the pairs.
+/// This only converges to the correct alignment if the neurons start close to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Icp<T: Float = Precision> {
+    /// Stop after this many iterations.
+    pub max_iterations: usize,
+    /// Stop once the RMS distance between point pairs improves by less than this fraction.
+    pub tolerance: T,
+    /// Ignore point pairs further apart than this,
+    /// e.g. from branches which are only present in one of the neurons.
+    pub max_dist: Option<T>,
+}
+
+impl<T: Float> Icp<T> {
+    pub fn new(max_iterations: usize, tolerance: T, max_dist: Option<T>) -> Self {
+        Self {
+            max_iterations,
+            tolerance,
+            max_dist,
+        }
+    }
+
+    /// Find the rigid transformation which moves the query onto the target.
+    /// If fewer than 3 point pairs are within the maximum distance,
+    /// the transformation found so far is returned, which may be the identity.
+    pub fn align(
+        &self,
+        query: &impl QueryNeuron<T>,
+        target: &impl TargetNeuron<T>,
+    ) -> Transform<T> {
+        let points = query.points();
+        let target_points = target.points();
+        let tangent = Vector3::x_axis();
+        let mut transform = Transform::identity();
+        let mut prev_rms: Option<T> = None;
+
+        for _ in 0..self.max_iterations {
+            let mut moved = Vec::with_capacity(points.len());
+            let mut matched = Vec::with_capacity(points.len());
+            let mut sum_dist2 = T::zero();
+            for point in points.iter() {
+                let p = transform.transform_point(point);
+                let (idx, dd) = target.nearest_match(&p, &tangent, None);
+                match self.max_dist {
+                    Some(m) if dd.dist > m => continue,
+                    _ => (),
+                }
+                sum_dist2 += dd.dist * dd.dist;
+                moved.push(Vector3::new(p[0], p[1], p[2]));
+                let q = target_points[idx];
+                matched.push(Vector3::new(q[0], q[1], q[2]));
+            }
+            if moved.len() < ICP_MIN_PAIRS {
+                break;
+            }
+            let rms = (sum_dist2 / T::from_precision(moved.len() as Precision)).sqrt();
+            if let Some(prev) = prev_rms {
+                if prev - rms <= self.tolerance * prev {
+                    break;
+                }
+            }
+            prev_rms = Some(rms);
+            transform = transform.then(&superimpose(&moved, &matched));
+        }
+        transform
+    }
+}
+
+impl<T: Float> Default for Icp<T> {
+    fn default() -> Self {
+        Self::new(ICP_MAX_ITERATIONS, T::from_precision(ICP_TOLERANCE), None)
+    }
+}
+
+/// Rotation and translation which minimises the squared distances between `from` and `to`
+/// ([Kabsch algorithm](https://en.wikipedia.org/wiki/Kabsch_algorithm)).
+fn superimpose<T: Float>(from: &[Vector3<T>], to: &[Vector3<T>]) -> Transform<T> {
+    let count = T::from_precision(from.len() as Precision);
+    let centroid = |vs: &[Vector3<T>]| vs.iter().fold(Vector3::zeros(), |sum, v| sum + v) / count;
+    let from_centroid = centroid(from);
+    let to_centroid = centroid(to);
+    let covariance = from
+        .iter()
+        .zip(to.iter())
+        .fold(Matrix3::zeros(), |sum, (f, t)| {
+            sum + (f - from_centroid) * (t - to_centroid).transpose()
+        });
+    let svd = covariance.svd(true, true);
+    let u = svd.u.expect("U was requested");
+    let v = svd.v_t.expect("V^T was requested").transpose();
+    // avoid reflections
+    let sign = (v * u.transpose()).determinant().signum();
+    let linear =
+        v * Matrix3::from_diagonal(&Vector3::new(T::one(), T::one(), sign)) * u.transpose();
+    Transform {
+        linear,
+        translation: to_centroid - linear * from_centroid,
+    }
+}
+
+/// Smallest number of landmarks which can define a thin-plate spline in 3D.
+const TPS_MIN_LANDMARKS: usize = 4;
+/// Number of terms in the affine part of a thin-plate spline in 3D: constant, x, y and z.
+const TPS_AFFINE_TERMS: usize = 4;
+
+/// Non-rigid warp which maps each source landmark exactly onto its target landmark,
+/// and bends as little as possible in between,
+/// e.g. for bridging between an EM space and an LM template.
+///
+/// Far from the landmarks, the warp approaches the affine transformation
+/// which best fits them.
+/// Landmark pairs can be read from a CSV with [io::landmarks](io/landmarks/index.html).
+#[derive(Debug, Clone, PartialEq)]
+pub struct ThinPlateSpline<T: Float = Precision> {
+    source: Vec<Vector3<T>>,
+    /// Weight of each source landmark's radial basis function.
+    weights: Vec<Vector3<T>>,
+    /// Constant term, then the coefficients of x, y and z.
+    affine: [Vector3<T>; TPS_AFFINE_TERMS],
+}
+
+impl<T: Float> ThinPlateSpline<T> {
+    /// Fit a spline through paired landmarks.
+    ///
+    /// At least 4 landmarks are required,
+    /// and the source landmarks must not all be coplanar or duplicated.
+    pub fn new(source: &[[T; 3]], target: &[[T; 3]]) -> Result<Self, Error> {
+        if source.len() != target.len() {
+            return Err(Error::MismatchedLengths {
+                points: source.len(),
+                other: target.len(),
+            });
+        }
+        if source.len() < TPS_MIN_LANDMARKS {
+            return Err(Error::TooFewPoints {
+                required: TPS_MIN_LANDMARKS,
+                actual: source.len(),
+            });
+        }
+        if let Some(idx) = source
+            .iter()
+            .zip(target.iter())
+            .position(|(s, t)| s.iter().chain(t.iter()).any(|v| !v.is_finite()))
+        {
+            return Err(Error::NonFinite { idx });
+        }
+        let source: Vec<_> = source
+            .iter()
+            .map(|p| Vector3::new(p[0], p[1], p[2]))
+            .collect();
+
+        // solve [[K, P], [P^T, 0]] [W; A] = [target; 0]
+        let n = source.len();
+        let size = n + TPS_AFFINE_TERMS;
+        let lhs = DMatrix::from_fn(size, size, |row, col| match (row < n, col < n) {
+            (true, true) => (source[row] - source[col]).norm(),
+            (true, false) => affine_basis(&source[row], col - n),
+            (false, true) => affine_basis(&source[col], row - n),
+            (false, false) => T::zero(),
+        });
+        let rhs = DMatrix::from_fn(
+            size,
+            3,
+            |row, col| {
+                if row < n {
+                    target[row][col]
+                } else {
+                    T::zero()
+                }
+            },
+        );
+        let solution = lhs
+            .lu()
+            .solve(&rhs)
+            .filter(|s| s.iter().all(|v| v.is_finite()))
+            .ok_or(Error::InvalidParameter(
+                "Landmarks are degenerate, e.g. coplanar or duplicated",
+            ))?;
+
+        let row =
+            |idx: usize| Vector3::new(solution[(idx, 0)], solution[(idx, 1)], solution[(idx, 2)]);
+        Ok(Self {
+            source,
+            weights: (0..n).map(row).collect(),
+            affine: [row(n), row(n + 1), row(n + 2), row(n + 3)],
+        })
+    }
+
+    /// Warp a point: the best-fit affine transformation plus the landmarks' weighted bending.
+    pub fn transform_point(&self, point: &[T; 3]) -> [T; 3] {
+        let p = Vector3::new(point[0], point[1], point[2]);
+        let mut out =
+            self.affine[0] + self.affine[1] * p[0] + self.affine[2] * p[1] + self.affine[3] * p[2];
+        for (landmark, weight) in self.source.iter().zip(self.weights.iter()) {
+            out += weight * (p - landmark).norm();
+        }
+        [out[0], out[1], out[2]]
+    }
+
+    /// Warp the points of any neuron into a new target neuron,
+    /// e.g. an [RStarPointTangents](type.RStarPointTangents.html).
+    ///
+    /// The warp can bend the neuron's local geometry,
+    /// so tangents and alpha values are calculated afresh from the warped points
+    /// using the given neighborhood.
+    pub fn transform_neuron<I: SpatialIndex<T>>(
+        &self,
+        neuron: &impl QueryNeuron<T>,
+        neighborhood: &Neighborhood<T>,
+    ) -> Result<IndexedPointTangents<T, I>, Error> {
+        IndexedPointTangents::new_with_neighborhood(
+            neuron
+                .points()
+                .iter()
+                .map(|p| self.transform_point(p))
+                .collect(),
+            neighborhood,
+        )
+    }
+}
+
+/// Constant, x, y or z term of the spline's affine part.
+fn affine_basis<T: Float>(point: &Vector3<T>, idx: usize) -> T {
+    if idx == 0 {
+        T::one()
+    } else {
+        point[idx - 1]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::RStarPointTangents;
+
+    const EPSILON: Precision = 1e-9;
+
+    fn assert_points_close_tol(a: &[Precision; 3], b: &[Precision; 3], tol: Precision) {
+        for (va, vb) in a.iter().zip(b.iter()) {
+            assert!((va - vb).abs() < tol, "{:?} != {:?}", a, b);
+        }
+    }
+
+    fn assert_points_close(a: &[Precision; 3], b: &[Precision; 3]) {
+        assert_points_close_tol(a, b, EPSILON);
+    }
+
+    #[test]
+    fn mirror() {
+        let m = Transform::mirror(&[10.0, 0.0, 0.0], &[2.0, 0.0, 0.0]).expect("Invalid transform");
+        assert_points_close(&m.transform_point(&[12.0, 1.0, 2.0]), &[8.0, 1.0, 2.0]);
+        assert_points_close(&m.transform_point(&[10.0, 5.0, 5.0]), &[10.0, 5.0, 5.0]);
+
+        let oblique =
+            Transform::mirror(&[1.0, 2.0, 3.0], &[1.0, -1.0, 0.5]).expect("Invalid transform");
+        let p = [4.0, -3.0, 7.0];
+        assert_points_close(&oblique.then(&oblique).transform_point(&p), &p);
+        assert_points_close(
+            &oblique.inverse().transform_point(&p),
+            &oblique.transform_point(&p),
+        );
+
+        assert!(Transform::<Precision>::mirror(&[0.0; 3], &[0.0; 3]).is_err());
+    }
+
+    #[test]
+    fn matrix_round_trip() {
+        let matrix = Matrix4::new(
+            2.0, 0.0, 0.0, 1.0, //
+            0.0, 0.0, -1.0, 2.0, //
+            0.0, 1.0, 0.0, 3.0, //
+            0.0, 0.0, 0.0, 1.0,
+        );
+        let t = Transform::from_matrix(&matrix).expect("Invalid transform");
+        assert_eq!(t.to_matrix(), matrix);
+        assert_points_close(&t.transform_point(&[1.0, 1.0, 1.0]), &[3.0, 1.0, 4.0]);
+        assert_points_close(
+            &t.then(&t.inverse()).transform_point(&[5.0, 6.0, 7.0]),
+            &[5.0, 6.0, 7.0],
+        );
+
+        let mut projective = matrix;
+        projective[(3, 0)] = 1.0;
+        assert!(Transform::from_matrix(&projective).is_err());
+        let mut singular = matrix;
+        singular[(0, 0)] = 0.0;
+        assert!(Transform::from_matrix(&singular).is_err());
+    }
+
+    #[test]
+    fn transform_neuron() {
+        let points = vec![
+            [0.0, 0.0, 0.0],
+            [1.0, 1.0, 0.0],
+            [2.0, 2.0, 0.1],
+            [3.0, 3.0, 0.0],
+        ];
+        let neuron = RStarPointTangents::new(points, 3).expect("Construction failed");
+        let scale = Transform::new(Matrix3::from_diagonal_element(3.0), Vector3::zeros())
+            .expect("Invalid transform");
+        let scaled: RStarPointTangents = scale.transform_neuron(&neuron).expect("Transform failed");
+
+        for (p, sp) in neuron.points().iter().zip(scaled.points().iter()) {
+            assert_points_close(&scale.transform_point(p), sp);
+        }
+        for (t, st) in neuron.tangents().iter().zip(scaled.tangents().iter()) {
+            assert!((st.norm() - 1.0).abs() < EPSILON);
+            assert!((t.dot(st) - 1.0).abs() < EPSILON);
+        }
+        assert_eq!(neuron.alphas(), scaled.alphas());
+    }
+
+    fn landmarks() -> Vec<[Precision; 3]> {
+        vec![
+            [0.0, 0.0, 0.0],
+            [10.0, 0.0, 0.0],
+            [0.0, 10.0, 0.0],
+            [0.0, 0.0, 10.0],
+            [10.0, 10.0, 10.0],
+            [5.0, 2.0, 8.0],
+        ]
+    }
+
+    #[test]
+    fn thin_plate_spline() {
+        let source = landmarks();
+        let target: Vec<_> = source
+            .iter()
+            .map(|p| [p[0] + 0.1 * p[1] * p[1], p[1], p[2] - 3.0])
+            .collect();
+        let tps = ThinPlateSpline::new(&source, &target).expect("Invalid landmarks");
+        for (s, t) in source.iter().zip(target.iter()) {
+            assert_points_close(&tps.transform_point(s), t);
+        }
+
+        // affine warps are reproduced everywhere
+        let affine = Transform::from_matrix(&Matrix4::new(
+            1.0, 0.2, 0.0, 5.0, //
+            0.0, 2.0, 0.0, -1.0, //
+            0.3, 0.0, 0.5, 0.0, //
+            0.0, 0.0, 0.0, 1.0,
+        ))
+        .expect("Invalid transform");
+        let target: Vec<_> = source.iter().map(|p| affine.transform_point(p)).collect();
+        let tps = ThinPlateSpline::new(&source, &target).expect("Invalid landmarks");
+        let p = [3.0, -7.0, 20.0];
+        assert_points_close(&tps.transform_point(&p), &affine.transform_point(&p));
+
+        let points: Vec<_> = (0..10).map(|i| [i as Precision, 1.0, 2.0]).collect();
+        let neuron = RStarPointTangents::new(points.clone(), 3).expect("Construction failed");
+        let warped: RStarPointTangents = tps
+            .transform_neuron(&neuron, &Neighborhood::Nearest(3))
+            .expect("Transform failed");
+        for (p, wp) in points.iter().zip(warped.points().iter()) {
+            assert_points_close(&affine.transform_point(p), wp);
+        }
+    }
+
+    #[test]
+    fn invalid_landmarks() {
+        let source = landmarks();
+        match ThinPlateSpline::new(&source, &source[1..]) {
+            Err(Error::MismatchedLengths { .. }) => (),
+            _ => panic!("Expected MismatchedLengths error"),
+        }
+        match ThinPlateSpline::new(&source[..3], &source[..3]) {
+            Err(Error::TooFewPoints { .. }) => (),
+            _ => panic!("Expected TooFewPoints error"),
+        }
+        let coplanar: Vec<_> = source.iter().map(|p| [p[0], p[1], 0.0]).collect();
+        match ThinPlateSpline::new(&coplanar, &source) {
+            Err(Error::InvalidParameter(_)) => (),
+            _ => panic!("Expected InvalidParameter error"),
+        }
+    }
+
+    #[test]
+    fn icp() {
+        let points: Vec<_> = (0..40)
+            .map(|i| {
+                let i = i as Precision * 0.3;
+                [5. * i.cos(), 5. * i.sin(), i]
+            })
+            .collect();
+        let target = RStarPointTangents::new(points.clone(), 5).expect("Construction failed");
+        let (sin, cos) = (0.05 as Precision).sin_cos();
+        let offset = Transform::new(
+            Matrix3::new(cos, -sin, 0.0, sin, cos, 0.0, 0.0, 0.0, 1.0),
+            Vector3::new(0.3, -0.2, 0.4),
+        )
+        .expect("Invalid transform");
+        let query: RStarPointTangents = offset.transform_neuron(&target).expect("Transform failed");
+
+        let aligned = Icp::default().align(&query, &target);
+        for p in points.iter() {
+            assert_points_close_tol(
+                &aligned.transform_point(&offset.transform_point(p)),
+                p,
+                1e-6,
+            );
+        }
+
+        // too few pairs within the maximum distance
+        let far = Icp::new(10, 1e-6, Some(1e-3)).align(&query, &target);
+        assert_eq!(far, Transform::identity());
+    }
+}